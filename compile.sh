@@ -2,7 +2,7 @@
 
 # install dependencies 
 apt-get update 
-apt-get install -y build-essential libasound2-dev
+apt-get install -y build-essential pkg-config libasound2-dev libssl-dev
 
 # install rust 
 curl https://sh.rustup.rs -sSf | sh
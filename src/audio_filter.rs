@@ -0,0 +1,212 @@
+use crate::{
+    channel_mixer::{ChannelConfig, ChannelMixer},
+    equalizer::{Equalizer, EqualizerConfig},
+    limiter::{Limiter, LimiterConfig},
+    normaliser::{NormalisationConfig, Normaliser},
+};
+use librespot::{core::spotify_id::SpotifyId, playback::mixer::AudioFilter};
+use std::sync::{Arc, Mutex};
+
+// Processing stages that live outside of librespot, shared between the
+// player's audio filter and the metadata pipe for runtime adjustments.
+pub struct Dsp {
+    pub normaliser: Normaliser,
+    pub equalizer: Equalizer,
+    pub channels: ChannelMixer,
+    pub limiter: Limiter,
+    // The item the normaliser corrects and its gain in dB, for the metadata
+    pub normalised: Option<(SpotifyId, f32)>,
+    // As loaded, the pipe adjusts the running stages at runtime
+    equalizer_config: EqualizerConfig,
+    channel_config: ChannelConfig,
+}
+
+pub type SharedDsp = Arc<Mutex<Dsp>>;
+
+impl Dsp {
+    pub fn new(
+        normalisation_config: NormalisationConfig,
+        equalizer_config: EqualizerConfig,
+        channel_config: ChannelConfig,
+        limiter_config: LimiterConfig,
+    ) -> SharedDsp {
+        Arc::new(Mutex::new(Dsp {
+            normaliser: Normaliser::new(normalisation_config),
+            equalizer: Equalizer::new(equalizer_config.clone()),
+            channels: ChannelMixer::new(channel_config.clone()),
+            limiter: Limiter::new(limiter_config),
+            normalised: None,
+            equalizer_config,
+            channel_config,
+        }))
+    }
+
+    // Only replaces the stages whose config changed since it was loaded, so
+    // that adjustments through the pipe survive a reload. Returns whether any did.
+    pub fn reconfigure(
+        &mut self,
+        normalisation_config: NormalisationConfig,
+        equalizer_config: EqualizerConfig,
+        channel_config: ChannelConfig,
+        limiter_config: LimiterConfig,
+    ) -> bool {
+        let mut changed = false;
+        if *self.normaliser.config() != normalisation_config {
+            self.normaliser.set_config(normalisation_config);
+            changed = true;
+        }
+        if self.equalizer_config != equalizer_config {
+            self.equalizer = Equalizer::new(equalizer_config.clone());
+            self.equalizer_config = equalizer_config;
+            changed = true;
+        }
+        if self.channel_config != channel_config {
+            self.channels = ChannelMixer::new(channel_config.clone());
+            self.channel_config = channel_config;
+            changed = true;
+        }
+        if *self.limiter.config() != limiter_config {
+            self.limiter = Limiter::new(limiter_config);
+            changed = true;
+        }
+        changed
+    }
+
+    fn is_active(&self) -> bool {
+        self.normaliser.is_enabled()
+            || self.equalizer.is_enabled()
+            || self.channels.is_active()
+            || self.limiter.is_enabled()
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        self.normaliser.process(samples);
+        self.equalizer.process(samples);
+        self.channels.process(samples);
+        self.limiter.process(samples);
+    }
+}
+
+// Wraps the mixer's (volume) filter and runs our own stages after it
+pub struct DspFilter {
+    volume_filter: Option<Box<dyn AudioFilter + Send>>,
+    dsp: SharedDsp,
+    buf: Mutex<Vec<f32>>,
+}
+
+impl DspFilter {
+    pub fn new(volume_filter: Option<Box<dyn AudioFilter + Send>>, dsp: SharedDsp) -> DspFilter {
+        DspFilter {
+            volume_filter,
+            dsp,
+            buf: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl AudioFilter for DspFilter {
+    fn modify_stream(&self, data: &mut [i16]) {
+        if let Some(ref volume_filter) = self.volume_filter {
+            volume_filter.modify_stream(data);
+        }
+
+        let mut dsp = match self.dsp.lock() {
+            Ok(dsp) => dsp,
+            Err(_) => return,
+        };
+        if !dsp.is_active() {
+            return;
+        }
+
+        let mut buf = self.buf.lock().unwrap();
+        buf.clear();
+        buf.extend(data.iter().map(|&s| f32::from(s) / 32768.0));
+        dsp.process(&mut buf);
+        for (out, &s) in data.iter_mut().zip(buf.iter()) {
+            *out = (s * 32768.0).clamp(-32768.0, 32767.0) as i16;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        equalizer::{db_to_ratio, BandConfig, FilterType},
+        normaliser::{NormalisationData, NormalisationType},
+    };
+
+    fn equalizer_config() -> EqualizerConfig {
+        EqualizerConfig {
+            enabled: true,
+            preamp: 0.0,
+            bands: vec![BandConfig {
+                filter_type: FilterType::Peaking,
+                frequency: 1000.0,
+                gain: 0.0,
+                q: 0.707,
+            }],
+        }
+    }
+
+    fn normalisation_config(pregain: f32) -> NormalisationConfig {
+        NormalisationConfig {
+            enabled: true,
+            normalisation_type: NormalisationType::Album,
+            pregain,
+            ..NormalisationConfig::default()
+        }
+    }
+
+    fn reconfigure(dsp: &SharedDsp, normalisation_config: NormalisationConfig) -> bool {
+        dsp.lock().unwrap().reconfigure(
+            normalisation_config,
+            equalizer_config(),
+            ChannelConfig::default(),
+            LimiterConfig::default(),
+        )
+    }
+
+    #[test]
+    fn reload_keeps_runtime_adjustments() {
+        let dsp = Dsp::new(
+            normalisation_config(0.0),
+            equalizer_config(),
+            ChannelConfig::default(),
+            LimiterConfig::default(),
+        );
+        {
+            let mut dsp = dsp.lock().unwrap();
+            assert!(dsp.equalizer.set_gain(0, 6.0));
+            dsp.channels.set_balance(-50);
+        }
+        assert!(!reconfigure(&dsp, normalisation_config(0.0)));
+
+        let dsp = dsp.lock().unwrap();
+        assert_eq!(dsp.equalizer.config().bands[0].gain, 6.0);
+        assert_eq!(dsp.channels.config().balance, -50);
+    }
+
+    #[test]
+    fn reload_keeps_the_normalisation_of_the_current_item() {
+        let dsp = Dsp::new(
+            normalisation_config(0.0),
+            equalizer_config(),
+            ChannelConfig::default(),
+            LimiterConfig::default(),
+        );
+        let data = NormalisationData {
+            track_gain_db: -3.0,
+            track_peak: 0.5,
+            album_gain_db: -6.0,
+            album_peak: 0.5,
+        };
+        dsp.lock().unwrap().normaliser.set_track(&data, true);
+        assert!(reconfigure(&dsp, normalisation_config(1.0)));
+
+        let mut samples = [1.0];
+        dsp.lock().unwrap().normaliser.process(&mut samples);
+        assert!((samples[0] - db_to_ratio(-3.0)).abs() < 1e-4);
+        assert_eq!(dsp.lock().unwrap().normaliser.config().pregain, 1.0);
+    }
+}
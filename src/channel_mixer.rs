@@ -0,0 +1,111 @@
+use crate::equalizer::CHANNELS;
+use serde::Serialize;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ChannelConfig {
+    pub mono: bool,
+    pub swap: bool,
+    // -100 (left only) ..= 100 (right only)
+    pub balance: i8,
+}
+
+pub struct ChannelMixer {
+    config: ChannelConfig,
+    gains: (f32, f32),
+}
+
+impl ChannelMixer {
+    pub fn new(config: ChannelConfig) -> ChannelMixer {
+        let gains = balance_gains(config.balance);
+        ChannelMixer { config, gains }
+    }
+
+    pub fn config(&self) -> &ChannelConfig {
+        &self.config
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.config.mono || self.config.swap || self.config.balance != 0
+    }
+
+    pub fn set_balance(&mut self, balance: i8) {
+        let balance = balance.clamp(-100, 100);
+        self.config.balance = balance;
+        self.gains = balance_gains(balance);
+    }
+
+    // Expects interleaved stereo samples
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if !self.is_active() {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(CHANNELS) {
+            let (mut left, mut right) = (frame[0], frame[1]);
+            if self.config.swap {
+                std::mem::swap(&mut left, &mut right);
+            }
+            if self.config.mono {
+                left = (left + right) * 0.5;
+                right = left;
+            }
+            frame[0] = left * self.gains.0;
+            frame[1] = right * self.gains.1;
+        }
+    }
+}
+
+// Attenuate the opposite channel linearly, leaving the favoured one untouched
+fn balance_gains(balance: i8) -> (f32, f32) {
+    let balance = f32::from(balance) / 100.0;
+    (1.0 - balance.max(0.0), 1.0 + balance.min(0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(config: ChannelConfig, samples: &[f32]) -> Vec<f32> {
+        let mut samples = samples.to_vec();
+        ChannelMixer::new(config).process(&mut samples);
+        samples
+    }
+
+    #[test]
+    fn inactive_by_default() {
+        assert!(!ChannelMixer::new(ChannelConfig::default()).is_active());
+        assert_eq!(process(ChannelConfig::default(), &[0.2, 0.6]), [0.2, 0.6]);
+    }
+
+    #[test]
+    fn downmixes_to_mono() {
+        let config = ChannelConfig {
+            mono: true,
+            ..ChannelConfig::default()
+        };
+        assert_eq!(process(config, &[0.2, 0.6, 1.0, -1.0]), [0.4, 0.4, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn swaps_channels() {
+        let config = ChannelConfig {
+            swap: true,
+            ..ChannelConfig::default()
+        };
+        assert_eq!(process(config, &[0.2, 0.6]), [0.6, 0.2]);
+    }
+
+    #[test]
+    fn balance_attenuates_the_other_side() {
+        let config = |balance| ChannelConfig {
+            balance,
+            ..ChannelConfig::default()
+        };
+        assert_eq!(process(config(50), &[0.8, 0.8]), [0.4, 0.8]);
+        assert_eq!(process(config(-100), &[0.8, 0.8]), [0.8, 0.0]);
+
+        let mut mixer = ChannelMixer::new(ChannelConfig::default());
+        mixer.set_balance(-128);
+        assert_eq!(mixer.config().balance, -100);
+        assert!(mixer.is_active());
+    }
+}
@@ -1,10 +1,23 @@
-use crate::{meta_pipe::MetaPipeConfig, version};
+use crate::{
+    channel_mixer::ChannelConfig,
+    credential_store::{CredentialStore, KeySource},
+    equalizer::{BandConfig, EqualizerConfig, FilterType},
+    event_hook::EventHookConfig,
+    idle_sink::IdleConfig,
+    limiter::LimiterConfig,
+    login_policy::LoginPolicyConfig,
+    meta_pipe::MetaPipeConfig,
+    normaliser::{NormalisationConfig, NormalisationType},
+    reconnect::ReconnectConfig,
+    version,
+    volume::{VolumeConfig, VolumeCurve},
+    webhook::{WebhookConfig, DEFAULT_EVENTS},
+};
 use hex;
 use librespot::{
     core::{
         self,
         authentication::Credentials,
-        cache::Cache,
         config::{ConnectConfig, DeviceType, SessionConfig, VolumeCtrl},
     },
     playback::{
@@ -13,126 +26,854 @@ use librespot::{
         mixer::{self, Mixer, MixerConfig},
     },
 };
-use serde::Deserialize;
+use serde::{
+    de::{self, DeserializeOwned, Deserializer, Visitor},
+    forward_to_deserialize_any, Deserialize, Serialize,
+};
 use sha1::{self, Digest, Sha1};
 use std::{
     convert::TryFrom,
-    fs::File,
-    io::{prelude::*, ErrorKind},
-    path::PathBuf,
-    process::exit,
+    env, fmt, fs,
+    io::ErrorKind,
+    net::SocketAddr,
+    path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
-use toml;
+use toml::{self, value::Table, Value};
 use url::Url;
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "kebab-case")]
+// Every key defaults to the section's `Default` impl, the single source of defaults
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case", default)]
 struct Authentication {
-    shared: Option<bool>,
+    shared: bool,
     username: Option<String>,
-    password: Option<String>,
-    device_name: Option<String>,
+    // At most one of these, `$CREDENTIALS_DIRECTORY/password` is used when none is set
+    #[serde(skip_serializing)]
+    password: Option<Secret>,
+    password_file: Option<String>,
+    password_env: Option<String>,
+    device_name: String,
+    // Discovery logins, anyone may take over when empty
+    allowed_users: Vec<String>,
+    lock_to_user: bool,
+    // Minutes
+    lock_idle_timeout: u64,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "kebab-case")]
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case", default)]
 struct Playback {
-    bitrate: Option<i16>,
-    enable_volume_normalisation: Option<bool>,
-    normalisation_pregain: Option<f32>,
-    volume_ctrl: Option<String>,
-    autoplay: Option<bool>,
-    gapless: Option<bool>,
+    bitrate: i16,
+    enable_volume_normalisation: bool,
+    normalisation_pregain: f32,
+    normalisation_type: String,
+    normalisation_target: f32,
+    // Follows `enable-volume-normalisation` unless set
+    limiter: Option<bool>,
+    limiter_threshold: f32,
+    limiter_lookahead_ms: u32,
+    limiter_release_ms: u32,
+    volume_ctrl: String,
+    volume_min_db: f64,
+    volume_max_db: f64,
+    autoplay: bool,
+    gapless: bool,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "kebab-case")]
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case", default)]
 struct Output {
+    // The backend's default device unless set
     device: Option<String>,
+    // The cached volume unless set
     initial_volume: Option<u16>,
-    mixer: Option<String>,
-    mixer_name: Option<String>,
-    mixer_card: Option<String>,
-    mixer_index: Option<u32>,
+    mixer: String,
+    mixer_name: String,
+    mixer_card: String,
+    mixer_index: u32,
+    // Forces a linear volume-ctrl on the alsa mixer unless set to false
     mixer_linear_volume: Option<bool>,
-    backend: Option<String>,
+    backend: String,
+    mono: bool,
+    swap_channels: bool,
+    balance: i8,
+    idle_timeout: u64,
+    standby_command: Option<String>,
+    wake_command: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "kebab-case")]
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case", default)]
 struct Misc {
-    disable_audio_cache: Option<bool>,
+    disable_audio_cache: bool,
     cache_location: Option<String>,
-    metadata_port: Option<u16>,
+    // Encrypts cached credentials, by default with a random key kept in the
+    // owner-only `credentials-key-file`. The machine id is readable by every
+    // local user, `credentials-key = "machine-id"` only protects copies of the
+    // cache taken to another machine.
+    encrypt_credentials: bool,
+    credentials_key: String,
+    credentials_key_file: Option<String>,
+    metadata_port: u16,
     ap_port: Option<u16>,
-    zeroconf_port: Option<u16>,
+    zeroconf_port: u16,
     proxy: Option<String>,
-    device_type: Option<String>,
+    device_type: String,
+    reconnect_initial_delay: u64,
+    reconnect_max_delay: u64,
+    reconnect_max_attempts: u32,
+    metrics_address: Option<String>,
+    onevent: Option<String>,
+    onevent_timeout: u64,
+    onevent_max_concurrent: usize,
+    // In `user_changed` and `takeover_rejected` pipe events
+    hash_usernames: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", default)]
+struct Equalizer {
+    // Enabled when any bands are configured unless set
+    enabled: Option<bool>,
+    preamp: f32,
+    bands: Vec<EqualizerBand>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct EqualizerBand {
+    #[serde(rename = "type")]
+    filter_type: String,
+    frequency: f32,
+    #[serde(default)]
+    gain: f32,
+    #[serde(default = "default_q")]
+    q: f32,
+}
+
+fn default_q() -> f32 {
+    0.707
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct Webhook {
+    url: String,
+    #[serde(default = "default_webhook_events")]
+    events: Vec<String>,
+    #[serde(skip_serializing)]
+    secret: Option<Secret>,
+    #[serde(default = "default_webhook_timeout")]
+    timeout: u64,
+    #[serde(default = "default_webhook_retries")]
+    retries: u32,
+}
+
+fn default_webhook_events() -> Vec<String> {
+    DEFAULT_EVENTS.iter().map(|event| event.to_string()).collect()
+}
+
+fn default_webhook_timeout() -> u64 {
+    5
+}
+
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+// Masked in `Debug` output and left out of `--print-config`, so that it never ends up in logs
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
 }
 
-#[derive(Deserialize, Debug)]
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "********")
+    }
+}
+
+// systemd's `CacheDirectory=`, then the XDG cache directory
+fn default_cache_location() -> String {
+    let location = env::var_os("CACHE_DIRECTORY")
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("XDG_CACHE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+                .map(|cache| cache.join("vollibrespot"))
+        })
+        .unwrap_or_else(|| PathBuf::from("/var/cache/vollibrespot"));
+    location.to_string_lossy().into_owned()
+}
+
+// As saved by librespot's `Cache`
+fn cached_volume(location: &Path) -> Option<u16> {
+    fs::read_to_string(location.join("volume"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// systemd's `StateDirectory=`, then the XDG state directory. Kept apart from
+// the cache so that copies of the cache don't carry the key.
+fn default_credentials_key_file() -> PathBuf {
+    env::var_os("STATE_DIRECTORY")
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("XDG_STATE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+                .map(|state| state.join("vollibrespot"))
+        })
+        .unwrap_or_else(|| PathBuf::from("/var/lib/vollibrespot"))
+        .join("credentials.key")
+}
+
+const CREDENTIALS_KEYS: &[&str] = &["key-file", "machine-id"];
+
+// Only reads the key, a missing key file is created once the daemon starts
+fn credentials_key(misc: &Misc, errors: &mut Vec<ConfigError>) -> (Option<KeySource>, Option<Vec<u8>>) {
+    if !misc.encrypt_credentials && misc.credentials_key_file.is_none() {
+        return (None, None);
+    }
+    let (key, source) = match misc.credentials_key.as_str() {
+        "key-file" => (
+            "Misc.credentials-key-file",
+            KeySource::File(
+                misc.credentials_key_file
+                    .as_ref()
+                    .map(PathBuf::from)
+                    .unwrap_or_else(default_credentials_key_file),
+            ),
+        ),
+        "machine-id" => ("Misc.credentials-key", KeySource::MachineId),
+        other => {
+            errors.push(ConfigError::invalid_choice(
+                "Misc.credentials-key",
+                other,
+                CREDENTIALS_KEYS,
+            ));
+            return (None, None);
+        }
+    };
+    match source.read() {
+        Ok(secret) => (Some(source), secret),
+        Err(message) => {
+            errors.push(ConfigError::new(key, message));
+            (None, None)
+        }
+    }
+}
+
+// Where systemd's `LoadCredential=` puts credentials
+const CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
+
+fn password(auth: &Authentication, errors: &mut Vec<ConfigError>) -> Option<String> {
+    let sources = [
+        auth.password.is_some(),
+        auth.password_file.is_some(),
+        auth.password_env.is_some(),
+    ];
+    if sources.iter().filter(|set| **set).count() > 1 {
+        errors.push(ConfigError::new(
+            "Authentication.password",
+            "only one of password, password-file and password-env can be set",
+        ));
+        return None;
+    }
+
+    let read = |key: &str, path: PathBuf, errors: &mut Vec<ConfigError>| {
+        fs::read_to_string(&path)
+            .map(|password| password.trim_end_matches(&['\r', '\n'][..]).to_string())
+            .map_err(|e| errors.push(ConfigError::new(key, format!("unable to read {:?}: {}", path, e))))
+            .ok()
+    };
+    if let Some(ref password) = auth.password {
+        Some(password.expose().to_string())
+    } else if let Some(ref path) = auth.password_file {
+        read("Authentication.password-file", PathBuf::from(path), errors)
+    } else if let Some(ref name) = auth.password_env {
+        env::var(name)
+            .map_err(|_| {
+                errors.push(ConfigError::new(
+                    "Authentication.password-env",
+                    format!("${} is not set", name),
+                ))
+            })
+            .ok()
+    } else {
+        let path = PathBuf::from(env::var_os(CREDENTIALS_DIRECTORY)?).join("password");
+        if !path.exists() {
+            return None;
+        }
+        info!("Using the password from ${}", CREDENTIALS_DIRECTORY);
+        read(&format!("${}", CREDENTIALS_DIRECTORY), path, errors)
+    }
+}
+
+#[derive(Serialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct Config {
+    #[serde(rename = "version")]
+    version: i64,
     authentication: Authentication,
     playback: Playback,
     output: Output,
     misc: Misc,
+    equalizer: Equalizer,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    webhook: Vec<Webhook>,
 }
 
-impl Config {
-    pub fn new(path: &str) -> Config {
-        // Read in config file
-        let mut file = match File::open(path) {
-            Ok(file) => file,
-            Err(e) => match e.kind() {
-                ErrorKind::NotFound => {
-                    println!("Unable to read config from {:?}, Using default config", path);
-                    return Config::default();
-                }
-                _ => {
-                    println!("There was a problem opening the file: {:#?}", e);
-                    exit(1)
+// A problem with a single config value, `key` is its path, e.g. `Playback.bitrate`
+#[derive(Debug)]
+pub struct ConfigError {
+    key: String,
+    message: String,
+}
+
+impl ConfigError {
+    fn new<K: Into<String>, M: Into<String>>(key: K, message: M) -> ConfigError {
+        ConfigError {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+
+    // For values that must be one of `choices`
+    fn invalid_choice(key: &str, value: &str, choices: &[&str]) -> ConfigError {
+        let suggestion = closest(value, choices)
+            .map(|choice| format!("did you mean {:?}? ", choice))
+            .unwrap_or_default();
+        ConfigError::new(
+            key,
+            format!(
+                "invalid value {:?} ({}expected one of: {})",
+                value,
+                suggestion,
+                choices.join(", ")
+            ),
+        )
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.key, self.message)
+        }
+    }
+}
+
+// Closest choice within a couple of typos
+fn closest<'a>(value: &str, choices: &[&'a str]) -> Option<&'a str> {
+    if value.parse::<f64>().is_ok() {
+        return None;
+    }
+    let value = value.to_lowercase();
+    choices
+        .iter()
+        .map(|choice| (edit_distance(&value, choice), *choice))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, choice)| choice)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { prev } else { prev + 1 };
+            prev = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(prev + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// The keys a section accepts, as declared to serde
+fn known_keys<T: DeserializeOwned>() -> &'static [&'static str] {
+    struct Keys(&'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for &mut Keys {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            self.0 = fields;
+            Err(de::Error::custom("only looking for the keys"))
+        }
+
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+            byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map enum
+            identifier ignored_any
+        }
+    }
+
+    let mut keys = Keys(&[]);
+    let _ = T::deserialize(&mut keys);
+    keys.0
+}
+
+// Typos would otherwise silently fall back to the default
+fn unknown_key(path: &str, key: &str, known: &[&str], strict: bool, errors: &mut Vec<ConfigError>) {
+    let suggestion = closest(key, known)
+        .map(|known| format!(", did you mean {:?}?", known))
+        .unwrap_or_default();
+    let error = ConfigError::new(format!("{}.{}", path, key), format!("unknown key{}", suggestion));
+    if strict {
+        errors.push(error);
+    } else {
+        warn!("Ignoring {}", error);
+    }
+}
+
+// Never quoted in errors or logs
+const SECRET_KEYS: &[&str] = &["Authentication.password"];
+
+// Deserializes a section key by key, so that every bad value gets reported
+fn section<T: DeserializeOwned + Default>(
+    root: &mut Table,
+    name: &str,
+    strict: bool,
+    errors: &mut Vec<ConfigError>,
+) -> T {
+    let table = match root.remove(name) {
+        Some(Value::Table(table)) => table,
+        Some(_) => {
+            errors.push(ConfigError::new(name, "must be a [section]"));
+            return T::default();
+        }
+        None => return T::default(),
+    };
+
+    let known = known_keys::<T>();
+    let mut valid = Table::new();
+    for (key, value) in table {
+        if !known.contains(&key.as_str()) {
+            unknown_key(name, &key, known, strict, errors);
+            continue;
+        }
+        let path = format!("{}.{}", name, key);
+        let mut single = Table::new();
+        single.insert(key, value);
+        match Value::Table(single.clone()).try_into::<T>() {
+            Ok(_) => valid.extend(single),
+            // Parse errors quote the value
+            Err(_) if SECRET_KEYS.contains(&path.as_str()) => {
+                errors.push(ConfigError::new(path, "invalid value, expected a string"))
+            }
+            Err(e) => errors.push(ConfigError::new(path, e.to_string())),
+        }
+    }
+    Value::Table(valid).try_into::<T>().unwrap_or_else(|e| {
+        errors.push(ConfigError::new(name, e.to_string()));
+        T::default()
+    })
+}
+
+// `section` only checks the keys of `[Equalizer]` itself
+fn equalizer_bands(root: &Table, strict: bool, errors: &mut Vec<ConfigError>) {
+    let bands = match root.get("Equalizer").and_then(|equalizer| equalizer.get("bands")) {
+        Some(Value::Array(bands)) => bands,
+        _ => return,
+    };
+    let known = known_keys::<EqualizerBand>();
+    for (idx, band) in bands.iter().enumerate() {
+        if let Value::Table(table) = band {
+            for key in table.keys().filter(|key| !known.contains(&key.as_str())) {
+                unknown_key(&format!("Equalizer.bands[{}]", idx), key, known, strict, errors);
+            }
+        }
+    }
+}
+
+fn webhooks(root: &mut Table, strict: bool, errors: &mut Vec<ConfigError>) -> Vec<Webhook> {
+    match root.remove("Webhook") {
+        Some(Value::Array(webhooks)) => webhooks
+            .into_iter()
+            .enumerate()
+            .filter_map(|(idx, webhook)| {
+                let known = known_keys::<Webhook>();
+                if let Value::Table(ref table) = webhook {
+                    for key in table.keys().filter(|key| !known.contains(&key.as_str())) {
+                        unknown_key(&format!("Webhook[{}]", idx), key, known, strict, errors);
+                    }
                 }
-            },
+                webhook
+                    .try_into::<Webhook>()
+                    .map_err(|e| {
+                        errors.push(ConfigError::new(format!("Webhook[{}]", idx), e.to_string()))
+                    })
+                    .ok()
+            })
+            .collect(),
+        Some(_) => {
+            errors.push(ConfigError::new(
+                "Webhook",
+                "must be an array of [[Webhook]] tables",
+            ));
+            Vec::new()
+        }
+        None => Vec::new(),
+    }
+}
+
+// Sections that can be overridden key by key
+const SECTIONS: &[&str] = &["Authentication", "Playback", "Output", "Misc", "Equalizer"];
+
+const ENV_PREFIX: &str = "VOLLIBRESPOT_";
+
+// A single `section.key=value` override, applied on top of the config file
+#[derive(Clone, Debug)]
+pub struct Override {
+    // Where it came from, e.g. `--set` or the environment variable
+    origin: String,
+    section: &'static str,
+    key: String,
+    raw: String,
+    value: Value,
+}
+
+impl Override {
+    // Values are parsed as TOML, anything that isn't valid TOML is taken as a string
+    pub fn new(origin: &str, section: &'static str, key: &str, value: &str) -> Override {
+        let raw = value.to_string();
+        let value = toml::from_str::<Table>(&format!("value = {}", value))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .unwrap_or_else(|| Value::String(raw.clone()));
+        Override {
+            origin: origin.to_string(),
+            section,
+            key: key.to_lowercase().replace('_', "-"),
+            raw,
+            value,
+        }
+    }
+
+    // Taken as a string when that's what the key expects, so that e.g. a
+    // numeric device name doesn't end up as a number
+    fn value<T: DeserializeOwned>(&self) -> Value {
+        let accepts = |value: &Value| {
+            let mut single = Table::new();
+            single.insert(self.key.clone(), value.clone());
+            Value::Table(single).try_into::<T>().is_ok()
         };
-        println!("Reading Config from {:?}", path);
-        let mut f_str = String::new();
-        file.read_to_string(&mut f_str).unwrap();
-        drop(file);
-
-        // Parse
-        let config: Config = match toml::from_str(&f_str) {
-            Ok(config) => config,
+        let raw = Value::String(self.raw.clone());
+        if !accepts(&self.value) && accepts(&raw) {
+            raw
+        } else {
+            self.value.clone()
+        }
+    }
+
+    // `VOLLIBRESPOT_<SECTION>_<KEY>` variables, e.g. `VOLLIBRESPOT_OUTPUT_MIXER_CARD`
+    pub fn from_env() -> Vec<Override> {
+        Override::from_vars(
+            env::vars_os()
+                .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?))),
+        )
+    }
+
+    fn from_vars<I: IntoIterator<Item = (String, String)>>(vars: I) -> Vec<Override> {
+        let mut overrides = vars
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let path = name.strip_prefix(ENV_PREFIX)?;
+                let section = SECTIONS.iter().find(|section| {
+                    path.len() > section.len() + 1
+                        && matches!(path.get(..section.len()), Some(name) if name.eq_ignore_ascii_case(section))
+                        && path[section.len()..].starts_with('_')
+                });
+                match section {
+                    Some(section) => Some(Override::new(
+                        &format!("${}", name),
+                        section,
+                        &path[section.len() + 1..],
+                        &value,
+                    )),
+                    None => {
+                        warn!(
+                            "Ignoring ${}, expected {}<SECTION>_<KEY> with a section of: {}",
+                            name,
+                            ENV_PREFIX,
+                            SECTIONS.join(", ")
+                        );
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        // The environment is unordered
+        overrides.sort_by(|a, b| a.origin.cmp(&b.origin));
+        overrides
+    }
+}
+
+impl FromStr for Override {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid override {:?}, expected \"section.key=value\"", s))?;
+        // Past this point only the path is quoted, the value may be a secret
+        let (section, key) = path
+            .split_once('.')
+            .filter(|(_, key)| !key.is_empty())
+            .ok_or_else(|| format!("invalid override {:?}, expected \"section.key=value\"", path))?;
+        let section = SECTIONS
+            .iter()
+            .find(|name| name.eq_ignore_ascii_case(section))
+            .ok_or_else(|| {
+                format!(
+                    "unknown section {:?} in {:?}, expected one of: {}",
+                    section,
+                    path,
+                    SECTIONS.join(", ")
+                )
+            })?;
+        Ok(Override::new("--set", section, key, value))
+    }
+}
+
+// Layout of the config file, bumped whenever keys are renamed or moved
+const CONFIG_VERSION: i64 = 1;
+
+// Returns the version the file was written for, 0.2.x files have none but
+// their layout is still current
+fn file_version(root: &Table) -> Result<i64, ConfigError> {
+    let version = match root.get("version") {
+        Some(Value::Integer(version)) => *version,
+        Some(value) => {
+            let message = format!("invalid value {}, expected a number", value);
+            return Err(ConfigError::new("version", message));
+        }
+        None => 0,
+    };
+    if version > CONFIG_VERSION {
+        let message = format!(
+            "{} is newer than the supported {}, is this config for a newer vollibrespot?",
+            version, CONFIG_VERSION
+        );
+        return Err(ConfigError::new("version", message));
+    }
+    Ok(version)
+}
+
+fn parse(contents: &str) -> Result<(Table, i64), ConfigError> {
+    let root = toml::from_str::<Table>(contents)
+        .map_err(|e| ConfigError::new("", format!("Malformed config: {}", e)))?;
+    let version = file_version(&root)?;
+    Ok((root, version))
+}
+
+// Stamps the current version into an older config file, keeping its comments
+// and the original as `<path>.bak`. Returns the version it was upgraded from.
+pub fn migrate_file(path: &str) -> Result<Option<i64>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+    let (_, version) = parse(&contents).map_err(|e| e.to_string())?;
+    if version == CONFIG_VERSION {
+        return Ok(None);
+    }
+
+    // Only unversioned files are older, and they lack no more than the key
+    let backup = format!("{}.bak", path);
+    fs::copy(path, &backup).map_err(|e| format!("Unable to back up to {:?}: {}", backup, e))?;
+    fs::write(path, stamp_version(&contents))
+        .map_err(|e| format!("Unable to write {:?}: {}", path, e))?;
+    Ok(Some(version))
+}
+
+// Top level keys have to come before the first [section]
+fn stamp_version(contents: &str) -> String {
+    format!("version = {}\n{}", CONFIG_VERSION, contents)
+}
+
+// Where the config comes from, kept around for reloads
+#[derive(Clone, Debug)]
+pub struct ConfigSource {
+    pub path: String,
+    pub overrides: Vec<Override>,
+    // Unknown keys are errors rather than warnings
+    pub strict: bool,
+}
+
+impl Config {
+    // Bad values are reported and left out, so that the rest can still be validated
+    pub fn load(source: &ConfigSource) -> (Config, Vec<ConfigError>) {
+        let path = &source.path;
+        let root = match fs::read_to_string(path) {
+            Ok(contents) => {
+                info!("Reading Config from {:?}", path);
+                match parse(&contents) {
+                    Ok((root, _)) => root,
+                    Err(e) => return (Config::default(), vec![e]),
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                info!("Unable to read config from {:?}, Using default config", path);
+                Table::new()
+            }
             Err(e) => {
-                println!("Malformed config key: {}", e.to_string());
-                exit(1)
+                let error =
+                    ConfigError::new("", format!("There was a problem opening {:?}: {}", path, e));
+                return (Config::default(), vec![error]);
+            }
+        };
+        Config::from_table(root, source)
+    }
+
+    // Missing sections and keys take their defaults
+    fn from_table(mut root: Table, source: &ConfigSource) -> (Config, Vec<ConfigError>) {
+        let mut errors = Vec::new();
+        for o in &source.overrides {
+            debug!("Overriding {}.{}", o.section, o.key);
+            let value = match o.section {
+                "Authentication" => o.value::<Authentication>(),
+                "Playback" => o.value::<Playback>(),
+                "Output" => o.value::<Output>(),
+                "Misc" => o.value::<Misc>(),
+                "Equalizer" => o.value::<Equalizer>(),
+                _ => o.value.clone(),
+            };
+            // Anything but a table is reported when the section is parsed
+            if let Value::Table(table) = root
+                .entry(o.section)
+                .or_insert_with(|| Value::Table(Table::new()))
+            {
+                table.insert(o.key.clone(), value);
             }
+        }
+
+        let strict = source.strict;
+        root.remove("version");
+        equalizer_bands(&root, strict, &mut errors);
+        let config = Config {
+            version: CONFIG_VERSION,
+            authentication: section(&mut root, "Authentication", strict, &mut errors),
+            playback: section(&mut root, "Playback", strict, &mut errors),
+            output: section(&mut root, "Output", strict, &mut errors),
+            misc: section(&mut root, "Misc", strict, &mut errors),
+            equalizer: section(&mut root, "Equalizer", strict, &mut errors),
+            webhook: webhooks(&mut root, strict, &mut errors),
         };
-        config
+        // Whatever is left wasn't recognised
+        let known = [SECTIONS, &["Webhook"]].concat();
+        for name in root.keys() {
+            let suggestion = closest(name, &known)
+                .map(|known| format!(", did you mean [{}]?", known))
+                .unwrap_or_default();
+            let error = ConfigError::new(name.as_str(), format!("unknown section{}", suggestion));
+            if strict {
+                errors.push(error);
+            } else {
+                warn!("Ignoring {}", error);
+            }
+        }
+        (config.resolve(), errors)
+    }
+
+    // Settles the defaults that depend on other values
+    fn resolve(mut self) -> Config {
+        let playback = &mut self.playback;
+        playback.limiter = Some(playback.limiter.unwrap_or(playback.enable_volume_normalisation));
+        let equalizer = &mut self.equalizer;
+        equalizer.enabled = Some(equalizer.enabled.unwrap_or(!equalizer.bands.is_empty()));
+
+        let output = &mut self.output;
+        if output.mixer == "alsa"
+            && output.mixer_linear_volume != Some(false)
+            && VolumeCurve::from_str(&playback.volume_ctrl) != Ok(VolumeCurve::Linear)
+        {
+            warn!("Setting <volume-ctrl> to linear for best compatibility");
+            playback.volume_ctrl = String::from("linear");
+        }
+        output.mixer_linear_volume = Some(output.mixer_linear_volume.unwrap_or(false));
+        self
+    }
+
+    // The fully resolved config, secrets are masked
+    pub fn to_toml(&self, overrides: &[Override]) -> Result<String, String> {
+        let mut out = String::from(
+            "# Resolved config, secrets are left out and have to be added back\n\
+             # Precedence: command line > VOLLIBRESPOT_<SECTION>_<KEY> environment > file > defaults\n",
+        );
+        for o in overrides {
+            out.push_str(&format!("# {}.{} set by {}\n", o.section, o.key, o.origin));
+        }
+        if self.authentication.password.is_some() {
+            out.push_str("# Authentication.password is set\n");
+        }
+        for (idx, webhook) in self.webhook.iter().enumerate() {
+            if webhook.secret.is_some() {
+                out.push_str(&format!("# Webhook[{}].secret is set\n", idx));
+            }
+        }
+        out.push('\n');
+        out.push_str(&toml::to_string(self).map_err(|e| e.to_string())?);
+        Ok(out)
     }
 }
 
 impl Default for Authentication {
     fn default() -> Authentication {
         Authentication {
-            shared: Some(true),
+            shared: true,
             username: None,
             password: None,
-            device_name: Some(String::from("Vollibrespot")),
+            password_file: None,
+            password_env: None,
+            device_name: String::from("Vollibrespot"),
+            allowed_users: Vec::new(),
+            lock_to_user: false,
+            lock_idle_timeout: 10,
         }
     }
 }
 
 impl Default for Playback {
     fn default() -> Playback {
+        let volume = VolumeConfig::default();
         Playback {
-            bitrate: Some(320),
-            enable_volume_normalisation: Some(true),
-            normalisation_pregain: None,
-            volume_ctrl: Some(String::from("linear")),
-            autoplay: Some(false),
-            gapless: Some(true),
+            bitrate: 320,
+            enable_volume_normalisation: false,
+            normalisation_pregain: 0.0,
+            normalisation_type: String::from("track"),
+            normalisation_target: -14.0,
+            limiter: None,
+            limiter_threshold: -1.0,
+            limiter_lookahead_ms: 5,
+            limiter_release_ms: 100,
+            volume_ctrl: volume.curve.to_string(),
+            volume_min_db: volume.min_db,
+            volume_max_db: volume.max_db,
+            autoplay: false,
+            gapless: true,
         }
     }
 }
@@ -140,14 +881,20 @@ impl Default for Playback {
 impl Default for Output {
     fn default() -> Output {
         Output {
-            device: Some(String::from("default")),
-            initial_volume: Some(50),
-            mixer: Some(String::from("softvol")),
-            mixer_name: None,
-            mixer_card: None,
-            mixer_index: None,
-            mixer_linear_volume: Some(true),
-            backend: Some(String::from("alsa")),
+            device: None,
+            initial_volume: None,
+            mixer: String::from("softvol"),
+            mixer_name: String::from("PCM"),
+            mixer_card: String::from("default"),
+            mixer_index: 0,
+            mixer_linear_volume: None,
+            backend: String::from("alsa"),
+            mono: false,
+            swap_channels: false,
+            balance: 0,
+            idle_timeout: 0,
+            standby_command: None,
+            wake_command: None,
         }
     }
 }
@@ -155,23 +902,37 @@ impl Default for Output {
 impl Default for Misc {
     fn default() -> Misc {
         Misc {
-            disable_audio_cache: Some(false),
-            cache_location: Some(String::from("/tmp")),
-            metadata_port: Some(5030),
+            disable_audio_cache: true,
+            cache_location: Some(default_cache_location()),
+            encrypt_credentials: false,
+            credentials_key: String::from("key-file"),
+            credentials_key_file: None,
+            metadata_port: 5030,
             ap_port: None,
-            zeroconf_port: Some(0),
+            zeroconf_port: 0,
             proxy: None,
-            device_type: Some(String::from("Speaker")),
+            device_type: String::from("speaker"),
+            reconnect_initial_delay: 1,
+            reconnect_max_delay: 300,
+            reconnect_max_attempts: 0,
+            metrics_address: None,
+            onevent: None,
+            onevent_timeout: 10,
+            onevent_max_concurrent: 4,
+            hash_usernames: false,
         }
     }
 }
 impl Default for Config {
     fn default() -> Config {
         Config {
+            version: CONFIG_VERSION,
             authentication: Authentication::default(),
             playback: Playback::default(),
             output: Output::default(),
             misc: Misc::default(),
+            equalizer: Equalizer::default(),
+            webhook: Vec::new(),
         }
     }
 }
@@ -180,6 +941,27 @@ fn device_id(name: &str) -> String {
     hex::encode(Sha1::digest(name.as_bytes()))
 }
 
+// What the player and Spirc are built from. librespot's configs don't
+// implement `PartialEq`, the config values they come from do.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayerSettings {
+    bitrate: i16,
+    gapless: bool,
+    normalisation: bool,
+    normalisation_pregain: f32,
+    normalisation_target: f32,
+    autoplay: bool,
+    initial_volume: Option<u16>,
+    device: Option<String>,
+    backend: String,
+    mixer: String,
+    mixer_name: String,
+    mixer_card: String,
+    mixer_index: u32,
+    mixer_linear_volume: Option<bool>,
+    idle: bool,
+}
+
 #[derive(Clone)]
 pub struct Setup {
     pub credentials: Option<Credentials>,
@@ -190,33 +972,70 @@ pub struct Setup {
 
     pub mixer: fn(Option<MixerConfig>) -> Box<dyn Mixer>,
 
-    pub cache: Option<Cache>,
+    pub use_audio_cache: bool,
+    pub credential_store: CredentialStore,
     pub player_config: PlayerConfig,
+    pub player_settings: PlayerSettings,
     pub mixer_config: MixerConfig,
     pub meta_config: MetaPipeConfig,
+    pub normalisation_config: NormalisationConfig,
+    pub equalizer_config: EqualizerConfig,
+    pub channel_config: ChannelConfig,
+    pub limiter_config: LimiterConfig,
+    pub reconnect_config: ReconnectConfig,
+    pub volume_config: VolumeConfig,
     pub enable_discovery: bool,
+    pub login_policy_config: LoginPolicyConfig,
     pub zeroconf_port: u16,
+    pub metrics_address: Option<SocketAddr>,
 }
 
+const BACKENDS: &[&str] = &["alsa", "pipe"];
+const MIXERS: &[&str] = &["softvol", "alsa"];
+const VOLUME_CURVES: &[&str] = &["linear", "log", "cubic", "fixed"];
+const BITRATES: &[&str] = &["96", "160", "320"];
+const NORMALISATION_TYPES: &[&str] = &["track", "album", "auto"];
+const DEVICE_TYPES: &[&str] = &[
+    "computer",
+    "tablet",
+    "smartphone",
+    "speaker",
+    "tv",
+    "avr",
+    "stb",
+    "audiodongle",
+];
+const FILTER_TYPES: &[&str] = &["peaking", "lowshelf", "highshelf", "lowpass", "highpass"];
+
 impl Setup {
-    // Todo: currently the default values are duplicated
-    pub fn from_config(mut config: Config) -> Setup {
-        // Setup cache
-        let use_audio_cache = !config.misc.disable_audio_cache.unwrap_or(true);
-        let cache = config
-            .misc
-            .cache_location
-            .map(|cache_location| Cache::new(PathBuf::from(cache_location), use_audio_cache));
+    // Reports the problems of both parsing and validating the config file
+    pub fn load(source: &ConfigSource) -> Result<Setup, Vec<ConfigError>> {
+        let (config, mut errors) = Config::load(source);
+        match Setup::from_config(config) {
+            Ok(setup) if errors.is_empty() => Ok(setup),
+            Ok(_) => Err(errors),
+            Err(setup_errors) => {
+                errors.extend(setup_errors);
+                Err(errors)
+            }
+        }
+    }
+
+    pub fn from_config(config: Config) -> Result<Setup, Vec<ConfigError>> {
+        let mut errors = Vec::new();
 
-        let device_name = config
-            .authentication
-            .device_name
-            .unwrap_or_else(|| String::from("Vollibrespot"));
+        // The cache is only created once the daemon starts
+        let use_audio_cache = !config.misc.disable_audio_cache;
+        let cache_location = config.misc.cache_location.as_ref().map(PathBuf::from);
+        let (key_source, key) = credentials_key(&config.misc, &mut errors);
+        let credential_store = CredentialStore::new(cache_location.clone(), key_source, key);
+
+        let password = password(&config.authentication, &mut errors);
+        let device_name = config.authentication.device_name;
 
         let credentials = {
             let username = config.authentication.username;
-            let password = config.authentication.password;
-            let cached_credentials = cache.as_ref().and_then(Cache::credentials);
+            let cached_credentials = credential_store.credentials();
 
             match (username, password, cached_credentials) {
                 (Some(username), Some(password), _) => {
@@ -230,134 +1049,357 @@ impl Setup {
                 (Some(ref username), _, Some(ref credentials)) if *username == credentials.username => {
                     Some(credentials.clone())
                 }
+                // Resume with the last user that connected
+                (None, _, Some(credentials)) => {
+                    info!("Using cached credentials for {}", credentials.username);
+                    Some(credentials)
+                }
                 _ => None,
             }
         };
 
-        let device = config.output.device.and_then(|d| {
-            if d.is_empty() {
-                error!("Invalid output device!");
-                exit(1);
-            } else {
-                Some(d)
+        let player_settings = PlayerSettings {
+            bitrate: config.playback.bitrate,
+            gapless: config.playback.gapless,
+            normalisation: config.playback.enable_volume_normalisation,
+            normalisation_pregain: config.playback.normalisation_pregain,
+            normalisation_target: config.playback.normalisation_target,
+            autoplay: config.playback.autoplay,
+            initial_volume: config.output.initial_volume,
+            device: config.output.device.clone(),
+            backend: config.output.backend.clone(),
+            mixer: config.output.mixer.clone(),
+            mixer_name: config.output.mixer_name.clone(),
+            mixer_card: config.output.mixer_card.clone(),
+            mixer_index: config.output.mixer_index,
+            mixer_linear_volume: config.output.mixer_linear_volume,
+            idle: config.output.idle_timeout > 0,
+        };
+
+        let device = config.output.device.filter(|device| {
+            if device.is_empty() {
+                errors.push(ConfigError::new("Output.device", "must not be empty"));
             }
+            !device.is_empty()
         });
 
-        match config.output.backend.as_ref().map(AsRef::as_ref) {
-            Some("pipe") => {
-                warn!("Using Pipe backend with device: {}", device.as_ref().unwrap());
-            }
-            Some("alsa") => {
-                warn!("Using Alsa backend with device: {}", device.as_ref().unwrap());
-            }
-            _ => {
-                error!("Unsupported backend");
-                exit(1)
-            }
+        let backend_name = config.output.backend;
+        let backend = if BACKENDS.contains(&backend_name.as_str()) {
+            audio_backend::find(Some(backend_name.clone()))
+        } else {
+            errors.push(ConfigError::invalid_choice(
+                "Output.backend",
+                &backend_name,
+                BACKENDS,
+            ));
+            None
+        }
+        .unwrap_or_else(|| audio_backend::find(None).unwrap());
+        if let Some(ref device) = device {
+            warn!("Using {} backend with device: {}", backend_name, device);
         }
-        let backend = audio_backend::find(config.output.backend).unwrap();
 
-        let mixer = mixer::find(config.output.mixer.as_ref()).expect("Invalid mixer");
+        let mixer_name = config.output.mixer;
+        let mixer = mixer::find(Some(&mixer_name))
+            .or_else(|| {
+                errors.push(ConfigError::invalid_choice("Output.mixer", &mixer_name, MIXERS));
+                mixer::find(None::<&String>)
+            })
+            .unwrap();
 
         let mixer_config = MixerConfig {
-            card: config
-                .output
-                .mixer_card
-                .unwrap_or_else(|| String::from("default")),
-            mixer: config.output.mixer_name.unwrap_or_else(|| String::from("PCM")),
-            index: config.output.mixer_index.unwrap_or(0),
-            mapped_volume: !config.output.mixer_linear_volume.unwrap_or(false),
+            card: config.output.mixer_card,
+            mixer: config.output.mixer_name,
+            index: config.output.mixer_index,
+            mapped_volume: config.output.mixer_linear_volume != Some(true),
         };
 
-        if config.output.mixer.as_ref().map(AsRef::as_ref) == Some("alsa")
-            && config.output.mixer_linear_volume != Some(false)
-            && config.playback.volume_ctrl.as_ref().map(AsRef::as_ref) != Some("linear")
-        {
-            warn!("Setting <volume-ctrl> to linear for best compatibility");
-            config.playback.volume_ctrl = Some(String::from("linear"));
+        // Volume curves are applied in front of the mixer, Spirc always maps linearly
+        let volume_ctrl = &config.playback.volume_ctrl;
+        let volume_config = VolumeConfig {
+            curve: VolumeCurve::from_str(volume_ctrl)
+                .map_err(|_| {
+                    errors.push(ConfigError::invalid_choice(
+                        "Playback.volume-ctrl",
+                        volume_ctrl,
+                        VOLUME_CURVES,
+                    ))
+                })
+                .unwrap_or(VolumeConfig::default().curve),
+            min_db: config.playback.volume_min_db,
+            max_db: config.playback.volume_max_db,
+        };
+        if volume_config.max_db > 0.0 || volume_config.min_db >= volume_config.max_db {
+            errors.push(ConfigError::new(
+                "Playback.volume-min-db",
+                "volume range must satisfy volume-min-db < volume-max-db <= 0",
+            ));
         }
+
         // Volume setup
         let initial_volume = config
             .output
             .initial_volume
-            .map(|volume| {
-                // let volume = volume.parse::<u16>().unwrap();
+            .and_then(|volume| {
                 if volume > 100 {
-                    error!("Initial volume must be in the range 0-100");
+                    errors.push(ConfigError::new(
+                        "Output.initial-volume",
+                        format!("{} is out of range, expected 0-100", volume),
+                    ));
+                    return None;
                 }
-                (i32::from(volume) * 0xFFFF / 100) as u16
+                Some((i32::from(volume) * 0xFFFF / 100) as u16)
+            })
+            .or_else(|| {
+                cache_location
+                    .as_ref()
+                    .and_then(|location| cached_volume(location))
             })
-            .or_else(|| cache.as_ref().and_then(Cache::volume))
             .unwrap_or(0x8000);
 
-        let zeroconf_port = config.misc.zeroconf_port.unwrap_or(0);
+        let zeroconf_port = config.misc.zeroconf_port;
+        let proxy = config
+            .misc
+            .proxy
+            .map(|proxy| ("Misc.proxy", proxy))
+            .or_else(|| {
+                std::env::var("http_proxy")
+                    .ok()
+                    .map(|proxy| ("$http_proxy", proxy))
+            })
+            .and_then(|(key, proxy)| {
+                let url = Url::parse(&proxy).map_err(|e| e.to_string()).and_then(|url| {
+                    if url.host().is_none() || url.port().is_none() {
+                        Err(String::from("missing host or port"))
+                    } else if url.scheme() != "http" {
+                        Err(String::from("only unsecure http:// proxies are supported"))
+                    } else {
+                        Ok(url)
+                    }
+                });
+                url.map_err(|e| {
+                    errors.push(ConfigError::new(
+                        key,
+                        format!(
+                            "invalid proxy url {:?}: {}, expected the format \"http://host:port\"",
+                            proxy, e
+                        ),
+                    ))
+                })
+                .ok()
+            });
         // Session config
-        let session_config =
-            SessionConfig {
+        let session_config = SessionConfig {
             user_agent: core::version::version_string(),
             device_id: device_id(&device_name),
-            proxy: config.misc.proxy.or_else(|| std::env::var("http_proxy").ok()).map(
-                |s| {
-                    match Url::parse(&s) {
-                Ok(url) => {
-                    if url.host().is_none() || url.port().is_none() {
-                        panic!("Invalid proxy url, only urls on the format \"http://host:port\" are allowed");
-                    }
-                    if url.scheme() != "http" {
-                        panic!("Only unsecure http:// proxies are supported");
-                    }
-                    url
-                },
-                Err(err) => panic!("Invalid proxy url: {}, only urls on the format \"http://host:port\" are allowed", err)
-            }
-                },
-            ),
+            proxy,
             ap_port: Some(443),
         };
+        let normalisation_type = &config.playback.normalisation_type;
+        let normalisation_config = NormalisationConfig {
+            enabled: config.playback.enable_volume_normalisation,
+            normalisation_type: NormalisationType::from_str(normalisation_type)
+                .map_err(|_| {
+                    errors.push(ConfigError::invalid_choice(
+                        "Playback.normalisation-type",
+                        normalisation_type,
+                        NORMALISATION_TYPES,
+                    ))
+                })
+                .unwrap_or(NormalisationType::Track),
+            pregain: config.playback.normalisation_pregain,
+            target: config.playback.normalisation_target,
+            limiter: config.playback.limiter == Some(true),
+        };
         let player_config = {
-            let bitrate = config
-                .playback
-                .bitrate
-                .map(|bitrate| Bitrate::try_from(bitrate).expect("Invalid bitrate"))
+            let bitrate = config.playback.bitrate;
+            let bitrate = Bitrate::try_from(bitrate)
+                .map_err(|_| {
+                    errors.push(ConfigError::invalid_choice(
+                        "Playback.bitrate",
+                        &bitrate.to_string(),
+                        BITRATES,
+                    ))
+                })
                 .unwrap_or_default();
 
+            // librespot applies the track gain from the first sample on, our
+            // audio filter corrects it once the item's ReplayGain header is read
             PlayerConfig {
                 bitrate,
-                normalisation: config.playback.enable_volume_normalisation.unwrap_or(false),
-                normalisation_pregain: config.playback.normalisation_pregain.unwrap_or_default(),
-                gapless: config.playback.gapless.unwrap_or(true),
+                normalisation: normalisation_config.enabled,
+                normalisation_pregain: normalisation_config.player_pregain(),
+                gapless: config.playback.gapless,
             }
         };
+        let limiter_config = LimiterConfig {
+            enabled: normalisation_config.limiter,
+            threshold: config.playback.limiter_threshold.min(0.0),
+            lookahead_ms: config.playback.limiter_lookahead_ms,
+            release_ms: config.playback.limiter_release_ms,
+        };
 
         let connect_config = {
+            let device_type = &config.misc.device_type;
             ConnectConfig {
                 name: device_name,
-                device_type: config
-                    .misc
-                    .device_type
-                    .map(|device_type| DeviceType::from_str(&device_type).expect("Invalid device type"))
-                    .unwrap_or_default(),
-                volume: initial_volume,
-                volume_ctrl: config
-                    .playback
-                    .volume_ctrl
-                    .map(|volume_ctrl| {
-                        VolumeCtrl::from_str(&volume_ctrl).expect("Invalid Volume Ctrl method")
+                device_type: DeviceType::from_str(&device_type.to_lowercase())
+                    .map_err(|_| {
+                        errors.push(ConfigError::invalid_choice(
+                            "Misc.device-type",
+                            device_type,
+                            DEVICE_TYPES,
+                        ))
                     })
                     .unwrap_or_default(),
-                autoplay: config.playback.autoplay.unwrap_or(false),
+                volume: initial_volume,
+                volume_ctrl: VolumeCtrl::Linear,
+                autoplay: config.playback.autoplay,
             }
         };
+        let webhooks = config
+            .webhook
+            .into_iter()
+            .enumerate()
+            .filter_map(|(idx, webhook)| {
+                let key = |name: &str| format!("Webhook[{}].{}", idx, name);
+                let url = match webhook.url.parse::<hyper::Uri>() {
+                    Ok(url) if matches!(url.scheme_str(), Some("http") | Some("https")) => url,
+                    Ok(url) => {
+                        errors.push(ConfigError::new(
+                            key("url"),
+                            format!("only http:// and https:// webhooks are supported: {}", url),
+                        ));
+                        return None;
+                    }
+                    Err(e) => {
+                        errors.push(ConfigError::new(
+                            key("url"),
+                            format!("invalid url {:?}: {}", webhook.url, e),
+                        ));
+                        return None;
+                    }
+                };
+                let events = webhook.events;
+                for event in events
+                    .iter()
+                    .filter(|event| !DEFAULT_EVENTS.contains(&event.as_str()))
+                {
+                    errors.push(ConfigError::invalid_choice(&key("events"), event, DEFAULT_EVENTS));
+                }
+                Some(WebhookConfig {
+                    url,
+                    events,
+                    secret: webhook.secret.filter(|secret| !secret.expose().is_empty()),
+                    // Seconds
+                    timeout: Duration::from_secs(webhook.timeout.max(1)),
+                    retries: webhook.retries,
+                })
+            })
+            .collect();
         let meta_config = {
             MetaPipeConfig {
-                port: config.misc.metadata_port.unwrap_or(5030),
+                port: config.misc.metadata_port,
                 version: format!("vollibrespot v{}", version::semver()),
+                volume: volume_config.clone(),
+                idle: IdleConfig {
+                    // Seconds, 0 keeps the sink open
+                    timeout: Some(config.output.idle_timeout)
+                        .filter(|timeout| *timeout > 0)
+                        .map(Duration::from_secs),
+                    standby_command: config.output.standby_command,
+                    wake_command: config.output.wake_command,
+                },
+                webhooks,
+                event_hook: EventHookConfig {
+                    command: config.misc.onevent.filter(|command| !command.is_empty()),
+                    // Seconds
+                    timeout: Duration::from_secs(config.misc.onevent_timeout.max(1)),
+                    max_concurrent: config.misc.onevent_max_concurrent.max(1),
+                },
+                hash_usernames: config.misc.hash_usernames,
+            }
+        };
+        let equalizer_config = {
+            let bands = config
+                .equalizer
+                .bands
+                .into_iter()
+                .enumerate()
+                .filter_map(|(idx, band)| {
+                    let key = |name: &str| format!("Equalizer.bands[{}].{}", idx, name);
+                    let filter_type = FilterType::from_str(&band.filter_type)
+                        .map_err(|_| {
+                            errors.push(ConfigError::invalid_choice(
+                                &key("type"),
+                                &band.filter_type,
+                                FILTER_TYPES,
+                            ))
+                        })
+                        .ok();
+                    if band.frequency <= 0.0 || band.frequency >= 22050.0 {
+                        errors.push(ConfigError::new(
+                            key("frequency"),
+                            format!("{}Hz is out of range, expected 0-22050Hz", band.frequency),
+                        ));
+                        return None;
+                    }
+                    Some(BandConfig {
+                        filter_type: filter_type?,
+                        frequency: band.frequency,
+                        gain: band.gain,
+                        q: Some(band.q).filter(|q| *q > 0.0).unwrap_or_else(default_q),
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            EqualizerConfig {
+                enabled: config.equalizer.enabled == Some(true),
+                preamp: config.equalizer.preamp,
+                bands,
             }
         };
-        let enable_discovery = config.authentication.shared.unwrap_or(true);
+        let channel_config = ChannelConfig {
+            mono: config.output.mono,
+            swap: config.output.swap_channels,
+            balance: config.output.balance,
+        };
+        if !(-100..=100).contains(&channel_config.balance) {
+            errors.push(ConfigError::new(
+                "Output.balance",
+                format!("{} is out of range, expected -100-100", channel_config.balance),
+            ));
+        }
+        // Seconds
+        let reconnect_config = ReconnectConfig {
+            initial_delay: Duration::from_secs(config.misc.reconnect_initial_delay.max(1)),
+            max_delay: Duration::from_secs(config.misc.reconnect_max_delay),
+            max_attempts: config.misc.reconnect_max_attempts,
+        };
+        let enable_discovery = config.authentication.shared;
+        let login_policy_config = LoginPolicyConfig {
+            allowed_users: config.authentication.allowed_users,
+            lock: config.authentication.lock_to_user,
+            lock_idle_timeout: Duration::from_secs(config.authentication.lock_idle_timeout * 60),
+        };
+        let metrics_address = config.misc.metrics_address.and_then(|address| {
+            address
+                .parse::<SocketAddr>()
+                .map_err(|e| {
+                    errors.push(ConfigError::new(
+                        "Misc.metrics-address",
+                        format!("invalid address {:?}: {}, expected \"host:port\"", address, e),
+                    ))
+                })
+                .ok()
+        });
 
-        Setup {
-            cache,
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Setup {
+            use_audio_cache,
+            credential_store,
             credentials,
             backend,
             device,
@@ -366,10 +1408,210 @@ impl Setup {
             session_config,
 
             player_config,
+            player_settings,
             connect_config,
             meta_config,
+            normalisation_config,
+            equalizer_config,
+            channel_config,
+            limiter_config,
+            reconnect_config,
+            volume_config,
             enable_discovery,
+            login_policy_config,
             zeroconf_port,
+            metrics_address,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(overrides: &[&str]) -> (Config, Vec<ConfigError>) {
+        let source = ConfigSource {
+            path: String::from("/nonexistent/vollibrespot.toml"),
+            overrides: overrides.iter().map(|o| o.parse().unwrap()).collect(),
+            strict: true,
+        };
+        Config::from_table(Table::new(), &source)
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("alsa", "alsa"), 0);
+        assert_eq!(edit_distance("", "log"), 3);
+        assert_eq!(edit_distance("lienar", "linear"), 2);
+        assert_eq!(edit_distance("pipes", "pipe"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn suggests_close_choices() {
+        assert_eq!(closest("Alas", BACKENDS), Some("alsa"));
+        assert_eq!(closest("logg", VOLUME_CURVES), Some("log"));
+        assert_eq!(closest("pulseaudio", BACKENDS), None);
+        // Numbers are never typos of names
+        assert_eq!(closest("96", &["9a"]), None);
+    }
+
+    #[test]
+    fn invalid_choices_list_the_alternatives() {
+        let error = ConfigError::invalid_choice("Output.backend", "alas", BACKENDS);
+        assert_eq!(
+            error.to_string(),
+            "Output.backend: invalid value \"alas\" (did you mean \"alsa\"? expected one of: alsa, pipe)"
+        );
+        let error = ConfigError::invalid_choice("Playback.bitrate", "128", BITRATES);
+        assert_eq!(
+            error.to_string(),
+            "Playback.bitrate: invalid value \"128\" (expected one of: 96, 160, 320)"
+        );
+    }
+
+    #[test]
+    fn numeric_strings_stay_strings() {
+        let (config, errors) = load(&[
+            "authentication.username=1234567890",
+            "authentication.device-name=2024",
+            "authentication.password=123456",
+            "misc.device-type=true",
+        ]);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(config.authentication.username.as_deref(), Some("1234567890"));
+        assert_eq!(config.authentication.device_name, "2024");
+        assert_eq!(config.authentication.password.unwrap().expose(), "123456");
+        assert_eq!(config.misc.device_type, "true");
+    }
+
+    #[test]
+    fn typed_values_are_parsed() {
+        let (config, errors) = load(&[
+            "playback.bitrate=96",
+            "output.initial-volume=50",
+            "playback.gapless=false",
+            "authentication.allowed-users=[\"alice\", \"bob\"]",
+        ]);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(config.playback.bitrate, 96);
+        assert_eq!(config.output.initial_volume, Some(50));
+        assert!(!config.playback.gapless);
+        assert_eq!(config.authentication.allowed_users, ["alice", "bob"]);
+    }
+
+    #[test]
+    fn bad_values_are_reported() {
+        let (_, errors) = load(&["playback.bitrate=fast"]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].key, "Playback.bitrate");
+    }
+
+    #[test]
+    fn unknown_band_keys_are_reported() {
+        let (config, errors) =
+            load(&["equalizer.bands=[{ type = \"peaking\", frequency = 1000.0, gian = 6.0 }]"]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].key, "Equalizer.bands[0].gian");
+        assert!(errors[0].message.contains("\"gain\""), "{}", errors[0]);
+        assert_eq!(config.equalizer.bands.len(), 1);
+    }
+
+    #[test]
+    fn secrets_stay_out_of_errors() {
+        let mut root = Table::new();
+        let mut auth = Table::new();
+        auth.insert(String::from("password"), Value::Integer(123_456));
+        root.insert(String::from("Authentication"), Value::Table(auth));
+        let source = ConfigSource {
+            path: String::new(),
+            overrides: Vec::new(),
+            strict: true,
+        };
+        let (_, errors) = Config::from_table(root, &source);
+        assert_eq!(errors.len(), 1);
+        assert!(!errors[0].to_string().contains("123456"), "{}", errors[0]);
+
+        for bad in &["authentcation.password=123456", "authentication=123456"] {
+            let error = bad.parse::<Override>().unwrap_err();
+            assert!(!error.contains("123456"), "{}", error);
         }
     }
+
+    #[test]
+    fn printed_config_loads_without_secrets() {
+        let (config, errors) = load(&["authentication.password=123456", "misc.metadata-port=6000"]);
+        assert!(errors.is_empty(), "{:?}", errors);
+        let printed = config.to_toml(&[]).unwrap();
+        assert!(!printed.contains("123456"), "{}", printed);
+        assert!(
+            printed.contains("# Authentication.password is set\n"),
+            "{}",
+            printed
+        );
+
+        let source = ConfigSource {
+            path: String::new(),
+            overrides: Vec::new(),
+            strict: true,
+        };
+        let (reloaded, errors) = Config::from_table(toml::from_str(&printed).unwrap(), &source);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(reloaded.authentication.password.is_none());
+        assert_eq!(reloaded.misc.metadata_port, 6000);
+    }
+
+    #[test]
+    fn reads_overrides_from_the_environment() {
+        let vars = [
+            ("PATH", "/usr/bin"),
+            ("VOLLIBRESPOT_OUTPUT_MIXER_CARD", "hw:1"),
+            ("VOLLIBRESPOT_AUTHENTICATION_PASSWORD", "123456"),
+            ("VOLLIBRESPOT_\u{e9}_KEY", "1"),
+            ("VOLLIBRESPOT_AUTHENTICATIO\u{e9}N_KEY", "1"),
+            ("VOLLIBRESPOT_PLAYBACK", "1"),
+        ];
+        let overrides = Override::from_vars(
+            vars.iter()
+                .map(|&(name, value)| (name.to_string(), value.to_string())),
+        );
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(overrides[0].origin, "$VOLLIBRESPOT_AUTHENTICATION_PASSWORD");
+        assert_eq!(
+            (overrides[0].section, overrides[0].key.as_str()),
+            ("Authentication", "password")
+        );
+        assert_eq!(
+            (overrides[1].section, overrides[1].key.as_str()),
+            ("Output", "mixer-card")
+        );
+
+        let source = ConfigSource {
+            path: String::new(),
+            overrides,
+            strict: true,
+        };
+        let (config, errors) = Config::from_table(Table::new(), &source);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(config.authentication.password.unwrap().expose(), "123456");
+        assert_eq!(config.output.mixer_card, "hw:1");
+    }
+
+    #[test]
+    fn versions_are_checked() {
+        assert_eq!(parse("[Playback]\nbitrate = 320\n").unwrap().1, 0);
+        assert_eq!(parse("version = 1\n").unwrap().1, CONFIG_VERSION);
+        assert_eq!(parse("version = 2\n").unwrap_err().key, "version");
+        assert_eq!(parse("version = \"1\"\n").unwrap_err().key, "version");
+    }
+
+    #[test]
+    fn stamped_files_keep_their_layout() {
+        let contents = "# Volumio\n[Playback]\n# Kbps\nbitrate = 320\n";
+        let stamped = stamp_version(contents);
+        assert!(stamped.ends_with(contents));
+        let (root, version) = parse(&stamped).unwrap();
+        assert_eq!(version, CONFIG_VERSION);
+        assert_eq!(root["Playback"]["bitrate"].as_integer(), Some(320));
+    }
 }
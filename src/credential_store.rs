@@ -0,0 +1,326 @@
+use crate::crypto;
+use librespot::core::{authentication::Credentials, cache::Cache};
+use std::{
+    fs::{self, DirBuilder, OpenOptions},
+    io::{self, ErrorKind, Write},
+    os::unix::fs::{symlink, DirBuilderExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+// Where librespot's `Cache` keeps reusable credentials, in clear
+const PLAIN_FILE: &str = "credentials.json";
+const SEALED_FILE: &str = "credentials.sealed";
+// Where librespot's credentials go while they are encrypted
+const DISCARD: &str = "/dev/null";
+
+const MACHINE_ID: &[&str] = &["/etc/machine-id", "/var/lib/dbus/machine-id"];
+const KEY_LEN: usize = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeySource {
+    // Only readable by its owner, `open` creates it with a random key
+    File(PathBuf),
+    // Readable by every local user, so this only protects copies of the cache
+    // taken to another machine
+    MachineId,
+}
+
+impl KeySource {
+    // None while the key file doesn't exist yet
+    pub fn read(&self) -> Result<Option<Vec<u8>>, String> {
+        let secret = match *self {
+            KeySource::File(ref path) => match fs::metadata(path) {
+                Ok(ref metadata) if metadata.permissions().mode() & 0o077 != 0 => {
+                    return Err(format!(
+                        "{:?} is accessible by other users, restrict it with chmod 600",
+                        path
+                    ));
+                }
+                Ok(_) => fs::read(path).map_err(|e| format!("unable to read {:?}: {}", path, e))?,
+                Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(format!("unable to read {:?}: {}", path, e)),
+            },
+            KeySource::MachineId => MACHINE_ID
+                .iter()
+                .find_map(|path| fs::read(path).ok())
+                .ok_or_else(|| format!("no machine id in {}", MACHINE_ID.join(" or ")))?,
+        };
+        if secret.is_empty() {
+            return Err(String::from("the key is empty"));
+        }
+        Ok(Some(crypto::hmac_sha256(&secret, b"vollibrespot credentials")))
+    }
+}
+
+// Reusable credentials, encrypted at rest when a key is configured. Without a
+// key this is librespot's own credential cache. Reading never touches the
+// disk otherwise, the cache is only set up by `open`.
+#[derive(Clone)]
+pub struct CredentialStore {
+    location: Option<PathBuf>,
+    key_source: Option<KeySource>,
+    key: Option<Vec<u8>>,
+}
+
+impl CredentialStore {
+    // `key` is what `key_source` read, if anything
+    pub fn new(
+        location: Option<PathBuf>,
+        key_source: Option<KeySource>,
+        key: Option<Vec<u8>>,
+    ) -> CredentialStore {
+        CredentialStore {
+            location,
+            key_source,
+            key,
+        }
+    }
+
+    // Creates the cache once the daemon starts, caching is disabled when the
+    // directory can't be created
+    pub fn open(&mut self, use_audio_cache: bool) -> Option<Cache> {
+        let location = self.location.clone()?;
+        // Holds credentials, keep it private
+        if let Err(e) = DirBuilder::new().recursive(true).mode(0o700).create(&location) {
+            warn!("Unable to create cache {:?}, caching disabled: {}", location, e);
+            self.location = None;
+            return None;
+        }
+        // Never let librespot cache credentials in clear when asked not to
+        if !self.create_key() {
+            self.location = None;
+            return None;
+        }
+        // Picks up credentials cached before encryption was enabled
+        self.seal_cached();
+        if let Err(e) = self.discard_plain(&location) {
+            warn!(
+                "Unable to stop librespot caching credentials in clear, caching disabled: {}",
+                e
+            );
+            self.location = None;
+            return None;
+        }
+        Some(Cache::new(location, use_audio_cache))
+    }
+
+    // librespot writes reusable credentials to the cache in clear after every
+    // login, while they are encrypted its credentials file leads to /dev/null
+    fn discard_plain(&self, location: &Path) -> io::Result<()> {
+        let path = location.join(PLAIN_FILE);
+        let discarded = matches!(fs::read_link(&path), Ok(ref target) if target == Path::new(DISCARD));
+        match (self.key.is_some(), discarded) {
+            (true, false) => symlink(DISCARD, &path),
+            (false, true) => fs::remove_file(&path),
+            _ => Ok(()),
+        }
+    }
+
+    // A missing key file is only created once the daemon starts
+    fn create_key(&mut self) -> bool {
+        let source = match self.key_source {
+            Some(ref source) if self.key.is_none() => source,
+            _ => return true,
+        };
+        let path = match *source {
+            KeySource::File(ref path) => path,
+            KeySource::MachineId => return false,
+        };
+        let created = path
+            .parent()
+            .map_or(Ok(()), |parent| {
+                DirBuilder::new().recursive(true).mode(0o700).create(parent)
+            })
+            .and_then(|_| {
+                OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)
+            })
+            .and_then(|mut file| file.write_all(&rand::random::<[u8; KEY_LEN]>()))
+            .map_err(|e| e.to_string())
+            .and_then(|_| source.read());
+        match created {
+            Ok(key) => self.key = key,
+            Err(e) => warn!(
+                "Unable to create credentials key {:?}, caching disabled: {}",
+                path, e
+            ),
+        }
+        self.key.is_some()
+    }
+
+    pub fn credentials(&self) -> Option<Credentials> {
+        let location = self.location.as_ref()?;
+        let key = match self.key {
+            Some(ref key) => key,
+            None => return read_plain(&location.join(PLAIN_FILE)),
+        };
+        let sealed = match read(&location.join(SEALED_FILE)) {
+            Some(sealed) => sealed,
+            // Not sealed by `open` yet
+            None => return read_plain(&location.join(PLAIN_FILE)),
+        };
+        let credentials = crypto::open(key, &sealed)
+            .and_then(|json| serde_json::from_slice::<Credentials>(&json).ok());
+        if credentials.is_none() {
+            warn!("Unable to decrypt cached credentials, was the key changed?");
+        }
+        credentials
+    }
+
+    pub fn save(&self, credentials: &Credentials) -> bool {
+        let location = match self.location {
+            Some(ref location) => location,
+            None => return false,
+        };
+        let json = serde_json::to_vec(credentials).expect("Unable to serialize credentials");
+        let (path, data) = match self.key {
+            Some(ref key) => (location.join(SEALED_FILE), crypto::seal(key, &json)),
+            None => (location.join(PLAIN_FILE), json),
+        };
+        let result = write_private(&path, &data);
+        if let Err(ref e) = result {
+            warn!("Unable to cache credentials in {:?}: {}", path, e);
+        }
+        result.is_ok()
+    }
+
+    // Moves credentials librespot cached in clear, before they were encrypted,
+    // into the encrypted store
+    fn seal_cached(&self) {
+        let location = match self.location {
+            Some(ref location) if self.key.is_some() => location,
+            _ => return,
+        };
+        let path = location.join(PLAIN_FILE);
+        let credentials = match read_plain(&path) {
+            Some(credentials) => credentials,
+            None => return,
+        };
+        if self.save(&credentials) {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Unable to remove plain text credentials {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+fn read(path: &Path) -> Option<Vec<u8>> {
+    match fs::read(path) {
+        Ok(data) => Some(data),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            warn!("Unable to read cached credentials from {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn read_plain(path: &Path) -> Option<Credentials> {
+    read(path).and_then(|json| serde_json::from_slice(&json).ok())
+}
+
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn location(name: &str) -> PathBuf {
+        let location = env::temp_dir().join(format!("vollibrespot-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&location);
+        location
+    }
+
+    fn credentials() -> Credentials {
+        Credentials::with_password(String::from("user"), String::from("password"))
+    }
+
+    #[test]
+    fn reading_leaves_the_disk_alone() {
+        let location = location("read");
+        let store = CredentialStore::new(Some(location.clone()), None, Some(b"key".to_vec()));
+        assert!(store.credentials().is_none());
+        store.seal_cached();
+        assert!(!location.exists());
+    }
+
+    #[test]
+    fn unusable_cache_disables_caching() {
+        let mut store = CredentialStore::new(Some(PathBuf::from("/dev/null/cache")), None, None);
+        assert!(store.open(true).is_none());
+        assert!(!store.save(&credentials()));
+    }
+
+    #[test]
+    fn seals_plain_credentials() {
+        let location = location("seal");
+        fs::create_dir_all(&location).unwrap();
+        let plain = CredentialStore::new(Some(location.clone()), None, None);
+        assert!(plain.save(&credentials()));
+
+        let sealed = CredentialStore::new(Some(location.clone()), None, Some(b"key".to_vec()));
+        assert_eq!(sealed.credentials().unwrap().username, "user");
+        sealed.seal_cached();
+        assert!(!location.join(PLAIN_FILE).exists());
+        assert!(plain.credentials().is_none());
+        assert_eq!(sealed.credentials().unwrap().username, "user");
+
+        let other = CredentialStore::new(Some(location.clone()), None, Some(b"other".to_vec()));
+        assert!(other.credentials().is_none());
+        fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[test]
+    fn creates_a_private_key_file() {
+        let location = location("key");
+        let source = KeySource::File(location.join("state/credentials.key"));
+        assert_eq!(source.read(), Ok(None));
+        let mut store = CredentialStore::new(Some(location.join("cache")), Some(source.clone()), None);
+        assert!(store.create_key());
+        let key = source.read().unwrap().unwrap();
+        assert_eq!(store.key, Some(key));
+
+        let path = location.join("state/credentials.key");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(source.read().is_err());
+        fs::remove_dir_all(&location).unwrap();
+    }
+
+    #[test]
+    fn missing_key_disables_caching() {
+        let source = KeySource::File(PathBuf::from("/dev/null/credentials.key"));
+        let mut store = CredentialStore::new(Some(location("nokey")), Some(source), None);
+        assert!(store.open(true).is_none());
+        assert!(store.credentials().is_none());
+    }
+
+    #[test]
+    fn librespot_credentials_are_discarded_while_sealed() {
+        let location = location("discard");
+        fs::create_dir_all(&location).unwrap();
+        let sealed = CredentialStore::new(Some(location.clone()), None, Some(b"key".to_vec()));
+        sealed.discard_plain(&location).unwrap();
+        sealed.discard_plain(&location).unwrap();
+        // As librespot's `Cache` writes them
+        fs::write(location.join(PLAIN_FILE), b"{\"username\":\"user\"}").unwrap();
+        assert_eq!(fs::read(location.join(PLAIN_FILE)).unwrap(), b"");
+
+        let plain = CredentialStore::new(Some(location.clone()), None, None);
+        plain.discard_plain(&location).unwrap();
+        assert!(fs::symlink_metadata(location.join(PLAIN_FILE)).is_err());
+        fs::remove_dir_all(&location).unwrap();
+    }
+}
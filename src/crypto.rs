@@ -0,0 +1,86 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+
+pub fn hmac_sha256(key: &[u8], msg: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any length");
+    mac.input(msg);
+    mac.result().code().to_vec()
+}
+
+// ChaCha20-Poly1305 with a key derived from `key`. Layout: version, nonce,
+// ciphertext and tag.
+pub fn seal(key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let nonce = rand::random::<[u8; NONCE_LEN]>();
+    let ciphertext = cipher(key)
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .expect("Unable to encrypt");
+    let mut sealed = vec![VERSION];
+    sealed.extend_from_slice(&nonce);
+    sealed.extend(ciphertext);
+    sealed
+}
+
+// None if `sealed` was tampered with or sealed with another key
+pub fn open(key: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < 1 + NONCE_LEN || sealed[0] != VERSION {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed[1..].split_at(NONCE_LEN);
+    cipher(key).decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+}
+
+fn cipher(key: &[u8]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(&hmac_sha256(key, b"seal")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_sha256_matches_rfc_4231() {
+        let mac = hmac_sha256(&[0x0b; 20], b"Hi There");
+        assert_eq!(
+            hex::encode(mac),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let sealed = seal(b"key", b"credentials");
+        assert_ne!(&sealed[1 + NONCE_LEN..], b"credentials");
+        assert_eq!(open(b"key", &sealed).unwrap(), b"credentials");
+        assert_eq!(open(b"key", &seal(b"key", b"")).unwrap(), b"");
+    }
+
+    #[test]
+    fn nonces_differ() {
+        assert_ne!(seal(b"key", b"credentials"), seal(b"key", b"credentials"));
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let sealed = seal(b"key", b"credentials");
+        for i in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 0x01;
+            assert_eq!(open(b"key", &tampered), None, "flipped byte {}", i);
+        }
+        assert_eq!(open(b"key", &sealed[..sealed.len() - 1]), None);
+        assert_eq!(open(b"key", &sealed[..1 + NONCE_LEN]), None);
+        assert_eq!(open(b"key", &[]), None);
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        assert_eq!(open(b"other", &seal(b"key", b"credentials")), None);
+    }
+}
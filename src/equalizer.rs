@@ -0,0 +1,323 @@
+use serde::Serialize;
+use std::{f64::consts::PI, str::FromStr};
+
+// librespot always decodes to 44.1kHz interleaved stereo
+pub const SAMPLE_RATE: f64 = 44100.0;
+pub const CHANNELS: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterType {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+impl FromStr for FilterType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "peaking" | "peak" => Ok(FilterType::Peaking),
+            "lowshelf" | "low-shelf" => Ok(FilterType::LowShelf),
+            "highshelf" | "high-shelf" => Ok(FilterType::HighShelf),
+            "lowpass" | "low-pass" => Ok(FilterType::LowPass),
+            "highpass" | "high-pass" => Ok(FilterType::HighPass),
+            _ => Err(format!("Unknown filter type: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BandConfig {
+    #[serde(rename = "type")]
+    pub filter_type: FilterType,
+    pub frequency: f32,
+    pub gain: f32,
+    pub q: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EqualizerConfig {
+    pub enabled: bool,
+    pub preamp: f32,
+    pub bands: Vec<BandConfig>,
+}
+
+impl Default for EqualizerConfig {
+    fn default() -> EqualizerConfig {
+        EqualizerConfig {
+            enabled: false,
+            preamp: 0.0,
+            bands: Vec::new(),
+        }
+    }
+}
+
+// Normalised biquad coefficients (a0 == 1) and per channel Direct Form I state
+#[derive(Clone, Debug, Default)]
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    // [x1, x2, y1, y2]
+    state: [[f64; 4]; CHANNELS],
+}
+
+impl Biquad {
+    // Coefficients from the RBJ Audio EQ Cookbook
+    fn new(band: &BandConfig) -> Biquad {
+        let a = 10f64.powf(f64::from(band.gain) / 40.0);
+        let w0 = 2.0 * PI * f64::from(band.frequency) / SAMPLE_RATE;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * f64::from(band.q));
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match band.filter_type {
+            FilterType::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            FilterType::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha),
+                (a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha,
+            ),
+            FilterType::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha),
+                (a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha,
+            ),
+            FilterType::LowPass => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterType::HighPass => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+        };
+
+        Biquad {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            state: [[0.0; 4]; CHANNELS],
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, channel: usize, x: f64) -> f64 {
+        let s = &mut self.state[channel];
+        let y = self.b0 * x + self.b1 * s[0] + self.b2 * s[1] - self.a1 * s[2] - self.a2 * s[3];
+        *s = [x, s[0], y, s[2]];
+        y
+    }
+}
+
+pub struct Equalizer {
+    config: EqualizerConfig,
+    preamp: f32,
+    filters: Vec<Biquad>,
+}
+
+impl Equalizer {
+    pub fn new(config: EqualizerConfig) -> Equalizer {
+        let filters = config.bands.iter().map(Biquad::new).collect();
+        let preamp = db_to_ratio(config.preamp);
+        Equalizer {
+            config,
+            preamp,
+            filters,
+        }
+    }
+
+    pub fn config(&self) -> &EqualizerConfig {
+        &self.config
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled && (!self.filters.is_empty() || self.config.preamp.abs() > f32::EPSILON)
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.config.enabled = enabled;
+        self.reset();
+    }
+
+    pub fn set_preamp(&mut self, preamp: f32) {
+        self.config.preamp = preamp;
+        self.preamp = db_to_ratio(preamp);
+    }
+
+    // Recalculate the coefficients of a single band, keeping its filter state
+    // so that adjustments during playback don't click
+    pub fn set_gain(&mut self, band: usize, gain: f32) -> bool {
+        match self.config.bands.get_mut(band) {
+            Some(band_config) => {
+                band_config.gain = gain;
+                let state = self.filters[band].state;
+                self.filters[band] = Biquad::new(band_config);
+                self.filters[band].state = state;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn reset(&mut self) {
+        for filter in self.filters.iter_mut() {
+            filter.state = [[0.0; 4]; CHANNELS];
+        }
+    }
+
+    // Expects interleaved stereo samples
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if !self.is_enabled() {
+            return;
+        }
+        for (idx, sample) in samples.iter_mut().enumerate() {
+            let channel = idx % CHANNELS;
+            let mut x = f64::from(*sample * self.preamp);
+            for filter in self.filters.iter_mut() {
+                x = filter.process(channel, x);
+            }
+            *sample = x as f32;
+        }
+    }
+}
+
+pub fn db_to_ratio(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn band(filter_type: FilterType, frequency: f32, gain: f32) -> BandConfig {
+        BandConfig {
+            filter_type,
+            frequency,
+            gain,
+            q: 0.707,
+        }
+    }
+
+    // Magnitude response at `frequency`, from the transfer function
+    fn response_db(filter: &Biquad, frequency: f64) -> f64 {
+        let w = 2.0 * PI * frequency / SAMPLE_RATE;
+        let (sin1, cos1) = w.sin_cos();
+        let (sin2, cos2) = (2.0 * w).sin_cos();
+        let magnitude = |c0: f64, c1: f64, c2: f64| {
+            let re = c0 + c1 * cos1 + c2 * cos2;
+            let im = -c1 * sin1 - c2 * sin2;
+            (re * re + im * im).sqrt()
+        };
+        let numerator = magnitude(filter.b0, filter.b1, filter.b2);
+        let denominator = magnitude(1.0, filter.a1, filter.a2);
+        20.0 * (numerator / denominator).log10()
+    }
+
+    fn assert_db(filter: &Biquad, frequency: f64, expected: f64) {
+        let actual = response_db(filter, frequency);
+        assert!(
+            (actual - expected).abs() < 0.1,
+            "{}Hz: {}dB != {}dB",
+            frequency,
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn peaking_boosts_around_its_frequency() {
+        let filter = Biquad::new(&band(FilterType::Peaking, 1000.0, 6.0));
+        assert_db(&filter, 1000.0, 6.0);
+        assert_db(&filter, 20.0, 0.0);
+        assert_db(&filter, 20000.0, 0.0);
+    }
+
+    #[test]
+    fn shelves_boost_one_end() {
+        let low = Biquad::new(&band(FilterType::LowShelf, 200.0, -6.0));
+        assert_db(&low, 10.0, -6.0);
+        assert_db(&low, 15000.0, 0.0);
+        let high = Biquad::new(&band(FilterType::HighShelf, 5000.0, 6.0));
+        assert_db(&high, 20.0, 0.0);
+        assert_db(&high, 21000.0, 6.0);
+    }
+
+    #[test]
+    fn passes_are_3db_down_at_their_cutoff() {
+        let low = Biquad::new(&band(FilterType::LowPass, 1000.0, 0.0));
+        assert_db(&low, 20.0, 0.0);
+        assert_db(&low, 1000.0, -3.0);
+        assert!(response_db(&low, 10000.0) < -35.0);
+        let high = Biquad::new(&band(FilterType::HighPass, 1000.0, 0.0));
+        assert_db(&high, 20000.0, 0.0);
+        assert_db(&high, 1000.0, -3.0);
+        assert!(response_db(&high, 100.0) < -35.0);
+    }
+
+    #[test]
+    fn bands_can_be_adjusted() {
+        let mut equalizer = Equalizer::new(EqualizerConfig {
+            enabled: true,
+            preamp: 0.0,
+            bands: vec![band(FilterType::Peaking, 1000.0, 0.0)],
+        });
+        let mut samples = [0.5, -0.5];
+        equalizer.process(&mut samples);
+        let state = equalizer.filters[0].state;
+
+        assert!(equalizer.set_gain(0, 6.0));
+        assert!(!equalizer.set_gain(1, 6.0));
+        assert_eq!(equalizer.config().bands[0].gain, 6.0);
+        assert_db(&equalizer.filters[0], 1000.0, 6.0);
+        assert_eq!(equalizer.filters[0].state, state);
+    }
+
+    #[test]
+    fn disabled_or_empty_leaves_samples_alone() {
+        let mut equalizer = Equalizer::new(EqualizerConfig {
+            enabled: true,
+            ..EqualizerConfig::default()
+        });
+        assert!(!equalizer.is_enabled());
+        equalizer.set_preamp(-6.0);
+        assert!(equalizer.is_enabled());
+        let mut samples = [1.0, 1.0];
+        equalizer.process(&mut samples);
+        assert!((samples[0] - db_to_ratio(-6.0)).abs() < 1e-6);
+
+        equalizer.set_enabled(false);
+        let mut samples = [1.0, 1.0];
+        equalizer.process(&mut samples);
+        assert_eq!(samples, [1.0, 1.0]);
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    os::unix::process::CommandExt,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventHookConfig {
+    pub command: Option<String>,
+    pub timeout: Duration,
+    pub max_concurrent: usize,
+}
+
+impl Default for EventHookConfig {
+    fn default() -> EventHookConfig {
+        EventHookConfig {
+            command: None,
+            timeout: Duration::from_secs(10),
+            max_concurrent: 4,
+        }
+    }
+}
+
+// Runs the `onevent` program, or the idle sink's commands, without ever blocking
+// the caller. Each run is watched by its own thread and killed, along with
+// anything it started, once it exceeds the timeout.
+#[derive(Default)]
+pub struct EventHook {
+    running: Arc<AtomicUsize>,
+}
+
+impl EventHook {
+    pub fn run(&self, config: &EventHookConfig, env: Vec<(&'static str, String)>) {
+        let command = match config.command {
+            Some(ref command) => command,
+            None => return,
+        };
+        if self.running.load(Ordering::SeqCst) >= config.max_concurrent {
+            warn!(
+                "{} hooks still running, skipping {:?}",
+                config.max_concurrent,
+                env.first().map(|(_, event)| event)
+            );
+            return;
+        }
+
+        let mut child = match Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .envs(env)
+            .stdin(Stdio::null())
+            // Its own process group, so that a timeout reaches whatever `sh` started
+            .process_group(0)
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Unable to run hook <{}>: {}", command, e);
+                return;
+            }
+        };
+        self.running.fetch_add(1, Ordering::SeqCst);
+
+        let running = self.running.clone();
+        let timeout = config.timeout;
+        thread::spawn(move || {
+            let deadline = Instant::now() + timeout;
+            loop {
+                match child.try_wait() {
+                    Ok(Some(status)) => {
+                        if !status.success() {
+                            debug!("Hook exited with {}", status);
+                        }
+                        break;
+                    }
+                    Ok(None) if Instant::now() >= deadline => {
+                        warn!("Hook timed out after {:?}, killing it", timeout);
+                        unsafe {
+                            libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+                        }
+                        let _ = child.wait();
+                        break;
+                    }
+                    Ok(None) => thread::sleep(Duration::from_millis(50)),
+                    Err(e) => {
+                        warn!("Unable to wait for hook: {}", e);
+                        break;
+                    }
+                }
+            }
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[test]
+    fn timeouts_kill_the_whole_process_group() {
+        let pid_file = env::temp_dir().join(format!("vollibrespot-hook-{}", std::process::id()));
+        let config = EventHookConfig {
+            command: Some(format!("sleep 30 & echo $! > {}; wait", pid_file.display())),
+            timeout: Duration::from_millis(200),
+            max_concurrent: 1,
+        };
+        let hook = EventHook::default();
+        hook.run(&config, Vec::new());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while hook.running.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(hook.running.load(Ordering::SeqCst), 0);
+        let pid = fs::read_to_string(&pid_file).unwrap();
+        let _ = fs::remove_file(&pid_file);
+        // Nothing may be left to reap it, a zombie is dead enough
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+        assert!(stat.is_empty() || stat.contains(") Z "), "{}", stat);
+    }
+}
@@ -0,0 +1,116 @@
+use librespot::playback::audio_backend::Sink;
+use std::{
+    io,
+    sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
+    thread,
+    time::Duration,
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IdleConfig {
+    pub timeout: Option<Duration>,
+    pub standby_command: Option<String>,
+    pub wake_command: Option<String>,
+}
+
+enum SinkCommand {
+    Start,
+    Stop,
+    Write(Vec<i16>),
+    Release,
+}
+
+type SinkRequest = (SinkCommand, Option<SyncSender<io::Result<()>>>);
+
+// Sinks aren't `Send`, so the real sink lives on its own thread where it can
+// be closed on request while the player is idle, and is reopened on the next write.
+pub struct IdleSink {
+    tx: Sender<SinkRequest>,
+}
+
+#[derive(Clone)]
+pub struct SinkControl {
+    tx: Sender<SinkRequest>,
+}
+
+impl IdleSink {
+    pub fn new(
+        backend: fn(Option<String>) -> Box<dyn Sink>,
+        device: Option<String>,
+    ) -> (IdleSink, SinkControl) {
+        let (tx, rx) = channel::<SinkRequest>();
+        thread::spawn(move || run(backend, device, rx));
+        (IdleSink { tx: tx.clone() }, SinkControl { tx })
+    }
+
+    fn request(&self, command: SinkCommand) -> io::Result<()> {
+        let (reply_tx, reply_rx) = sync_channel(1);
+        self.tx
+            .send((command, Some(reply_tx)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Sink thread exited"))?;
+        reply_rx
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::BrokenPipe, "Sink thread exited")))
+    }
+}
+
+impl Sink for IdleSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.request(SinkCommand::Start)
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.request(SinkCommand::Stop)
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        self.request(SinkCommand::Write(data.to_vec()))
+    }
+}
+
+impl SinkControl {
+    pub fn release(&self) {
+        let _ = self.tx.send((SinkCommand::Release, None));
+    }
+}
+
+fn run(backend: fn(Option<String>) -> Box<dyn Sink>, device: Option<String>, rx: Receiver<SinkRequest>) {
+    let mut sink: Option<Box<dyn Sink>> = None;
+    let mut started = false;
+
+    for (command, reply_tx) in rx {
+        let result = match command {
+            SinkCommand::Start => {
+                started = true;
+                sink.get_or_insert_with(|| backend(device.clone())).start()
+            }
+            SinkCommand::Stop => {
+                started = false;
+                sink.as_mut().map_or(Ok(()), |sink| sink.stop())
+            }
+            SinkCommand::Write(data) => match sink {
+                Some(ref mut sink) => sink.write(&data),
+                None => {
+                    info!("Reopening idle sink");
+                    let sink = sink.get_or_insert_with(|| backend(device.clone()));
+                    started = true;
+                    sink.start().and_then(|_| sink.write(&data))
+                }
+            },
+            SinkCommand::Release => {
+                if let Some(mut sink) = sink.take() {
+                    info!("Releasing idle sink");
+                    if started {
+                        let _ = sink.stop();
+                    }
+                }
+                started = false;
+                Ok(())
+            }
+        };
+        if let Some(reply_tx) = reply_tx {
+            let _ = reply_tx.send(result);
+        }
+    }
+    debug!("Sink thread exited");
+}
@@ -0,0 +1,148 @@
+use crate::equalizer::{db_to_ratio, CHANNELS, SAMPLE_RATE};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LimiterConfig {
+    pub enabled: bool,
+    // dBFS
+    pub threshold: f32,
+    pub lookahead_ms: u32,
+    pub release_ms: u32,
+}
+
+impl Default for LimiterConfig {
+    fn default() -> LimiterConfig {
+        LimiterConfig {
+            enabled: false,
+            threshold: -1.0,
+            lookahead_ms: 5,
+            release_ms: 100,
+        }
+    }
+}
+
+// Look-ahead peak limiter: the output is delayed by `lookahead_ms` so that
+// the gain can be brought down smoothly before a peak reaches the output.
+pub struct Limiter {
+    config: LimiterConfig,
+    threshold: f32,
+    lookahead: usize,
+    attack_coeff: f32,
+    release_coeff: f32,
+    delay: VecDeque<[f32; CHANNELS]>,
+    hold_gain: f32,
+    hold_frames: usize,
+    gain: f32,
+}
+
+impl Limiter {
+    pub fn new(config: LimiterConfig) -> Limiter {
+        let frames = |ms: u32| (f64::from(ms) * SAMPLE_RATE / 1000.0).max(1.0);
+        let lookahead = frames(config.lookahead_ms);
+        // Reach the target gain (within ~1%) by the time the peak is played
+        let attack_coeff = (-5.0 / lookahead).exp() as f32;
+        let release_coeff = (-1.0 / frames(config.release_ms)).exp() as f32;
+        Limiter {
+            threshold: db_to_ratio(config.threshold),
+            lookahead: lookahead as usize,
+            attack_coeff,
+            release_coeff,
+            delay: VecDeque::with_capacity(lookahead as usize + 1),
+            hold_gain: 1.0,
+            hold_frames: 0,
+            gain: 1.0,
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &LimiterConfig {
+        &self.config
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    // Expects interleaved stereo samples
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if !self.is_enabled() {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(CHANNELS) {
+            let peak = frame.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+            let target = if peak > self.threshold {
+                self.threshold / peak
+            } else {
+                1.0
+            };
+
+            // Hold the lowest gain for the length of the look-ahead window
+            if target <= self.hold_gain || self.hold_frames == 0 {
+                self.hold_gain = target;
+                self.hold_frames = self.lookahead;
+            } else {
+                self.hold_frames -= 1;
+            }
+
+            let coeff = if self.hold_gain < self.gain {
+                self.attack_coeff
+            } else {
+                self.release_coeff
+            };
+            self.gain = self.hold_gain + coeff * (self.gain - self.hold_gain);
+
+            self.delay.push_back([frame[0], frame[1]]);
+            let delayed = if self.delay.len() > self.lookahead {
+                self.delay.pop_front().unwrap_or_default()
+            } else {
+                [0.0; CHANNELS]
+            };
+            for (out, s) in frame.iter_mut().zip(delayed.iter()) {
+                *out = (s * self.gain).clamp(-self.threshold, self.threshold);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> Limiter {
+        Limiter::new(LimiterConfig {
+            enabled: true,
+            ..LimiterConfig::default()
+        })
+    }
+
+    #[test]
+    fn never_exceeds_the_ceiling() {
+        let mut limiter = limiter();
+        let ceiling = db_to_ratio(limiter.config().threshold);
+        let mut samples = (0..44100)
+            .map(|idx| if idx % 1000 < 10 { 1.0 } else { 0.5 } * if idx % 2 == 0 { 1.0 } else { -1.0 })
+            .collect::<Vec<f32>>();
+        limiter.process(&mut samples);
+        let peak = samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+        assert!(peak <= ceiling, "{} > {}", peak, ceiling);
+    }
+
+    #[test]
+    fn delays_quiet_signals_unchanged() {
+        let mut limiter = limiter();
+        let lookahead = limiter.lookahead;
+        let mut samples = vec![0.25; 2 * (lookahead + 10)];
+        limiter.process(&mut samples);
+        assert!(samples[..2 * lookahead].iter().all(|s| *s == 0.0));
+        assert!(samples[2 * lookahead..].iter().all(|s| *s == 0.25));
+    }
+
+    #[test]
+    fn disabled_leaves_samples_alone() {
+        let mut limiter = Limiter::new(LimiterConfig::default());
+        let mut samples = [1.0, -1.0];
+        limiter.process(&mut samples);
+        assert_eq!(samples, [1.0, -1.0]);
+    }
+}
@@ -0,0 +1,160 @@
+use librespot::playback::player::PlayerEvent;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoginPolicyConfig {
+    // Anyone may log in when empty
+    pub allowed_users: Vec<String>,
+    // Keep the current user until playback has been idle for `lock_idle_timeout`
+    pub lock: bool,
+    pub lock_idle_timeout: Duration,
+}
+
+impl LoginPolicyConfig {
+    fn allows(&self, username: &str) -> bool {
+        self.allowed_users.is_empty()
+            || self
+                .allowed_users
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(username))
+    }
+}
+
+// Decides whether a discovery login may take over from the current user
+pub struct LoginPolicy {
+    config: LoginPolicyConfig,
+    // Of the last session, also while it reconnects
+    user: Option<String>,
+    playing: bool,
+    idle_since: Instant,
+}
+
+impl LoginPolicy {
+    pub fn new(config: LoginPolicyConfig) -> LoginPolicy {
+        LoginPolicy {
+            config,
+            user: None,
+            playing: false,
+            idle_since: Instant::now(),
+        }
+    }
+
+    pub fn config(&self) -> &LoginPolicyConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: LoginPolicyConfig) {
+        self.config = config;
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    // A new user counts as active until they have been idle for the timeout
+    pub fn session_started(&mut self, username: &str) {
+        self.user = Some(username.to_string());
+        self.playing = false;
+        self.idle_since = Instant::now();
+    }
+
+    pub fn handle_event(&mut self, event: &PlayerEvent) {
+        let playing = match *event {
+            PlayerEvent::Started { .. } | PlayerEvent::Playing { .. } => true,
+            PlayerEvent::Paused { .. } | PlayerEvent::Stopped { .. } => false,
+            _ => return,
+        };
+        if self.playing && !playing {
+            self.idle_since = Instant::now();
+        }
+        self.playing = playing;
+    }
+
+    // The reason a takeover by `username` is rejected, if it is
+    pub fn check(&self, username: &str) -> Result<(), &'static str> {
+        if !self.config.allows(username) {
+            return Err("not_allowed");
+        }
+        match self.user {
+            Some(ref user) if self.config.lock && !user.eq_ignore_ascii_case(username) => {
+                if self.playing || self.idle_since.elapsed() < self.config.lock_idle_timeout {
+                    Err("device_locked")
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librespot::core::spotify_id::{SpotifyAudioType, SpotifyId};
+
+    const TRACK: SpotifyId = SpotifyId {
+        id: 0,
+        audio_type: SpotifyAudioType::Track,
+    };
+
+    fn policy(allowed_users: &[&str], lock: bool) -> LoginPolicy {
+        LoginPolicy::new(LoginPolicyConfig {
+            allowed_users: allowed_users.iter().map(|user| user.to_string()).collect(),
+            lock,
+            lock_idle_timeout: Duration::from_secs(600),
+        })
+    }
+
+    #[test]
+    fn anyone_without_an_allow_list() {
+        let mut policy = policy(&[], false);
+        assert_eq!(policy.check("alice"), Ok(()));
+        policy.session_started("alice");
+        assert_eq!(policy.check("bob"), Ok(()));
+    }
+
+    #[test]
+    fn only_allowed_users() {
+        let policy = policy(&["Alice"], false);
+        assert_eq!(policy.check("alice"), Ok(()));
+        assert_eq!(policy.check("bob"), Err("not_allowed"));
+    }
+
+    #[test]
+    fn locked_to_the_last_user() {
+        let mut policy = policy(&[], true);
+        assert_eq!(policy.check("bob"), Ok(()));
+        policy.session_started("alice");
+        assert_eq!(policy.user(), Some("alice"));
+        assert_eq!(policy.check("bob"), Err("device_locked"));
+        assert_eq!(policy.check("ALICE"), Ok(()));
+    }
+
+    #[test]
+    fn lock_expires_once_idle() {
+        let mut policy = policy(&[], true);
+        policy.session_started("alice");
+        policy.idle_since = Instant::now().checked_sub(Duration::from_secs(601)).unwrap();
+        assert_eq!(policy.check("bob"), Ok(()));
+
+        policy.handle_event(&PlayerEvent::Playing {
+            play_request_id: 0,
+            track_id: TRACK,
+            position_ms: 0,
+            duration_ms: 0,
+        });
+        policy.idle_since = Instant::now().checked_sub(Duration::from_secs(601)).unwrap();
+        assert_eq!(policy.check("bob"), Err("device_locked"));
+
+        policy.handle_event(&PlayerEvent::Paused {
+            play_request_id: 0,
+            track_id: TRACK,
+            position_ms: 0,
+            duration_ms: 0,
+        });
+        assert_eq!(policy.check("bob"), Err("device_locked"));
+        policy.idle_since = Instant::now().checked_sub(Duration::from_secs(601)).unwrap();
+        assert_eq!(policy.check("bob"), Ok(()));
+    }
+}
@@ -1,7 +1,10 @@
 #[macro_use]
 extern crate log;
 use env_logger::{fmt, Builder};
-use futures::{sync::mpsc::UnboundedReceiver, Async, Future, Poll, Stream};
+use futures::{
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    Async, Future, Poll, Stream,
+};
 use librespot::{
     connect::{
         discovery::{discovery, DiscoveryStream},
@@ -16,7 +19,7 @@ use librespot::{
     playback::{
         audio_backend::{Sink, BACKENDS},
         config::PlayerConfig,
-        mixer::{Mixer, MixerConfig},
+        mixer::{AudioFilter, Mixer, MixerConfig},
         player::{Player, PlayerEvent},
     },
 };
@@ -24,22 +27,59 @@ use std::{
     env,
     io::{self, Write},
     mem,
+    net::{SocketAddr, UdpSocket},
     process::exit,
-    sync::{mpsc::channel, Arc},
-    time::Instant,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        mpsc::channel,
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    runtime::{
+        current_thread,
+        current_thread::{Handle, Runtime},
+    },
+    timer::{Delay, Interval},
 };
-use tokio::runtime::{
-    current_thread,
-    current_thread::{Handle, Runtime},
+use tokio_signal::{
+    ctrl_c,
+    unix::{Signal, SIGHUP, SIGTERM, SIGUSR1},
+    IoStream,
 };
-use tokio_signal::{ctrl_c, IoStream};
 
+mod audio_filter;
+mod channel_mixer;
 mod config_parser;
+mod credential_store;
+mod crypto;
+mod equalizer;
+mod event_hook;
+mod idle_sink;
+mod limiter;
+mod login_policy;
 mod meta_pipe;
+mod metrics;
+mod normaliser;
+mod reconnect;
+mod replay_gain;
+mod sd_notify;
 mod version;
+mod volume;
+mod webhook;
 use crate::{
-    config_parser::{Config, Setup},
-    meta_pipe::{MetaPipe, MetaPipeConfig},
+    audio_filter::{Dsp, DspFilter, SharedDsp},
+    config_parser::{Config, ConfigSource, Override, PlayerSettings, Setup},
+    credential_store::CredentialStore,
+    idle_sink::IdleSink,
+    login_policy::LoginPolicy,
+    meta_pipe::{MetaMsgs, MetaPipe, MetaPipeConfig},
+    metrics::{SessionState, METRICS},
+    normaliser::NormalisationConfig,
+    reconnect::Backoff,
+    replay_gain::ReplayGain,
+    volume::{VolumeConfig, VolumeMixer},
 };
 
 fn usage(program: &str, opts: &getopts::Options) -> String {
@@ -107,7 +147,7 @@ fn list_backends() {
     }
 }
 
-fn setup(args: &[String]) -> Setup {
+fn setup(args: &[String]) -> (ConfigSource, Setup) {
     let mut opts = getopts::Options::new();
     opts.optopt(
         "c",
@@ -122,7 +162,57 @@ fn setup(args: &[String]) -> Setup {
         "Audio backend to use. Use '?' to list options",
         "BACKEND",
     )
-    .optflag("", "verbose", "Enable verbose output");
+    .optopt(
+        "n",
+        "name",
+        "Device name, overrides Authentication.device-name",
+        "NAME",
+    )
+    .optopt(
+        "d",
+        "device",
+        "Audio device to use, overrides Output.device",
+        "DEVICE",
+    )
+    .optopt(
+        "b",
+        "bitrate",
+        "Bitrate (96, 160 or 320), overrides Playback.bitrate",
+        "BITRATE",
+    )
+    .optopt(
+        "",
+        "metadata-port",
+        "Port of the metadata pipe, overrides Misc.metadata-port",
+        "PORT",
+    )
+    .optmulti(
+        "",
+        "set",
+        "Override any config key, e.g. 'output.device=hw:1'. Can be repeated",
+        "SECTION.KEY=VALUE",
+    )
+    .optflag("", "verbose", "Enable verbose output")
+    .optflag(
+        "",
+        "check-config",
+        "Validate the config file, report all problems and exit",
+    )
+    .optflag(
+        "",
+        "strict",
+        "Treat unknown config sections and keys as errors instead of warnings",
+    )
+    .optflag(
+        "",
+        "migrate-config",
+        "Upgrade an older config file to the current format, keeping a .bak copy, and exit",
+    )
+    .optflag(
+        "",
+        "print-config",
+        "Print the fully resolved config, defaults included, and exit",
+    );
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -147,17 +237,129 @@ fn setup(args: &[String]) -> Setup {
         exit(0);
     }
 
-    println!("{}", version::version());
-
     let config_file = matches
         .opt_str("config")
         .unwrap_or_else(|| String::from("config.toml"));
-    Setup::from_config(Config::new(&config_file))
+
+    // Later overrides win: environment, then `--set`, then the dedicated flags
+    let mut overrides = Override::from_env();
+    for o in matches.opt_strs("set") {
+        match o.parse::<Override>() {
+            Ok(o) => overrides.push(o),
+            Err(e) => {
+                eprintln!("error: {}\n{}", e, usage(&args[0], &opts));
+                exit(1)
+            }
+        }
+    }
+    let flags = [
+        ("name", "Authentication", "device-name"),
+        ("device", "Output", "device"),
+        ("bitrate", "Playback", "bitrate"),
+        ("metadata-port", "Misc", "metadata-port"),
+        ("backend", "Output", "backend"),
+    ];
+    for &(flag, section, key) in flags.iter() {
+        if let Some(value) = matches.opt_str(flag) {
+            overrides.push(Override::new(&format!("--{}", flag), section, key, &value));
+        }
+    }
+    let source = ConfigSource {
+        path: config_file.clone(),
+        overrides,
+        strict: matches.opt_present("strict"),
+    };
+
+    if matches.opt_present("migrate-config") {
+        match config_parser::migrate_file(&config_file) {
+            Ok(Some(version)) => println!("{}: upgraded from version {}", config_file, version),
+            Ok(None) => println!("{}: already up to date", config_file),
+            Err(e) => {
+                eprintln!("{}: {}", config_file, e);
+                exit(1)
+            }
+        }
+        exit(0)
+    }
+
+    // Keeps stdout clean so that the output can be saved as a config file,
+    // once the secrets it leaves out are added back
+    if matches.opt_present("print-config") {
+        let (config, _) = Config::load(&source);
+        match config.to_toml(&source.overrides) {
+            Ok(toml) => print!("{}", toml),
+            Err(e) => {
+                eprintln!("Unable to print config: {}", e);
+                exit(1)
+            }
+        }
+        if let Err(errors) = Setup::load(&source) {
+            eprintln!("{}: {} problem(s) found", config_file, errors.len());
+            for e in errors {
+                eprintln!("  {}", e);
+            }
+            exit(1)
+        }
+        exit(0)
+    }
+
+    println!("{}", version::version());
+    let setup = Setup::load(&source);
+
+    if matches.opt_present("check-config") {
+        match setup {
+            Ok(_) => {
+                println!("{}: OK", config_file);
+                exit(0)
+            }
+            Err(errors) => {
+                eprintln!("{}: {} problem(s) found", config_file, errors.len());
+                for e in errors {
+                    eprintln!("  {}", e);
+                }
+                exit(1)
+            }
+        }
+    }
+
+    match setup {
+        Ok(setup) => (source, setup),
+        Err(errors) => {
+            for e in errors {
+                error!("{}", e);
+            }
+            error!("Invalid config, run with --check-config for details");
+            exit(1)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum SignalKind {
+    Interrupt,
+    Terminate,
+    Reload,
+    DumpState,
+}
+
+fn signals() -> IoStream<SignalKind> {
+    let unix_signal = |signal, kind| Signal::new(signal).flatten_stream().map(move |_| kind);
+    Box::new(
+        ctrl_c()
+            .flatten_stream()
+            .map(|_| SignalKind::Interrupt)
+            .select(unix_signal(SIGTERM, SignalKind::Terminate))
+            .select(unix_signal(SIGHUP, SignalKind::Reload))
+            .select(unix_signal(SIGUSR1, SignalKind::DumpState)),
+    )
 }
 
 struct Main {
+    config_source: ConfigSource,
     cache: Option<Cache>,
+    credential_store: CredentialStore,
     player_config: PlayerConfig,
+    player_settings: PlayerSettings,
     session_config: SessionConfig,
     connect_config: ConnectConfig,
     meta_config: MetaPipeConfig,
@@ -165,63 +367,119 @@ struct Main {
     device: Option<String>,
     mixer: fn(Option<MixerConfig>) -> Box<dyn Mixer>,
     mixer_config: MixerConfig,
+    volume_config: VolumeConfig,
+    // For the next player, the running one may still correct by the previous one
+    normalisation_config: NormalisationConfig,
+    dsp: SharedDsp,
     handle: Handle,
 
     discovery: Option<DiscoveryStream>,
-    signal: IoStream<()>,
+    login_policy: LoginPolicy,
+    zeroconf_port: u16,
+    metrics_address: Option<SocketAddr>,
+    signal: IoStream<SignalKind>,
+    reload_tx: UnboundedSender<()>,
+    reload_rx: UnboundedReceiver<()>,
 
     spirc: Option<Arc<Spirc>>,
     spirc_task: Option<SpircTask>,
+    // Whether the running player can release its sink while idle
+    idle_sink: bool,
+    // The running player's volume, carried over when it is rebuilt
+    mixer_volume: Option<Arc<AtomicU16>>,
+    // Whether the player is busy with an item, it is only rebuilt when it isn't
+    playing: bool,
     connect: Box<dyn Future<Item = Session, Error = io::Error>>,
 
     shutdown: bool,
     last_credentials: Option<Credentials>,
-    auto_connect_times: Vec<Instant>,
+    // Handed over by discovery, kept once they authenticated
+    discovery_login: Option<Credentials>,
+    backoff: Backoff,
+    ready: bool,
+    watchdog: Option<Interval>,
 
     player_event_channel: Option<UnboundedReceiver<PlayerEvent>>,
+    replay_gain: Option<ReplayGain>,
 
     session: Option<Session>,
     meta_pipe: Option<MetaPipe>,
-    reconnecting: bool,
+    // Until there is a MetaPipe
+    meta_socket: UdpSocket,
 }
 
 impl Main {
-    fn new(handle: Handle, setup: Setup) -> Main {
+    fn new(handle: Handle, config_source: ConfigSource, setup: Setup) -> Main {
+        let (reload_tx, reload_rx) = unbounded();
+        let mut credential_store = setup.credential_store;
+        let cache = credential_store.open(setup.use_audio_cache);
         let mut task = Main {
             handle: handle.clone(),
-            cache: setup.cache,
+            config_source,
+            cache,
+            credential_store,
             session_config: setup.session_config,
             player_config: setup.player_config,
+            player_settings: setup.player_settings,
             connect_config: setup.connect_config,
             meta_config: setup.meta_config,
             backend: setup.backend,
             device: setup.device,
             mixer: setup.mixer,
             mixer_config: setup.mixer_config,
+            volume_config: setup.volume_config,
+            normalisation_config: setup.normalisation_config.clone(),
+            dsp: Dsp::new(
+                setup.normalisation_config,
+                setup.equalizer_config,
+                setup.channel_config,
+                setup.limiter_config,
+            ),
 
             connect: Box::new(futures::future::empty()),
             discovery: None,
+            zeroconf_port: setup.zeroconf_port,
+            metrics_address: setup.metrics_address,
             spirc: None,
             spirc_task: None,
+            idle_sink: false,
+            mixer_volume: None,
+            playing: false,
             shutdown: false,
             last_credentials: None,
-            auto_connect_times: Vec::new(),
-            signal: Box::new(ctrl_c().flatten_stream()),
+            discovery_login: None,
+            backoff: Backoff::new(setup.reconnect_config),
+            ready: false,
+            watchdog: sd_notify::watchdog_interval()
+                .map(|interval| Interval::new(Instant::now() + interval, interval)),
+            signal: signals(),
+            reload_tx,
+            reload_rx,
 
             player_event_channel: None,
+            replay_gain: None,
+            login_policy: LoginPolicy::new(setup.login_policy_config),
 
             session: None,
             meta_pipe: None,
-            reconnecting: false,
+            meta_socket: UdpSocket::bind("127.0.0.1:0").expect("Unable to open metadata socket"),
         };
 
+        if let Some(ref addr) = task.metrics_address {
+            if let Err(e) = metrics::serve(&handle, addr) {
+                error!("Unable to serve metrics on {}: {}", addr, e);
+            }
+        }
+
         if setup.enable_discovery {
             let config = task.connect_config.clone();
             let device_id = task.session_config.device_id.clone();
 
             task.discovery = Some(
-                discovery(&handle, config, device_id, setup.zeroconf_port).expect("Discovery error!"),
+                discovery(&handle, config, device_id, task.zeroconf_port).expect("Discovery error!"),
             );
+            sd_notify::status("Waiting for a Spotify Connect client");
+            task.notify_ready();
         }
 
         if let Some(credentials) = setup.credentials {
@@ -232,10 +490,338 @@ impl Main {
     }
 
     fn credentials(&mut self, credentials: Credentials) {
+        self.connect(credentials, None);
+    }
+
+    // Re-read the config file and apply whatever can change without dropping the session
+    fn reload(&mut self) {
+        info!("Reloading config from {:?}", self.config_source.path);
+        // Keep running with the current config when the new one is invalid
+        let setup = match Setup::load(&self.config_source) {
+            Ok(setup) => setup,
+            Err(errors) => {
+                for e in &errors {
+                    error!("{}", e);
+                }
+                warn!("Not reloading invalid config");
+                return;
+            }
+        };
+        let mut applied = Vec::new();
+        let mut restart_required = Vec::new();
+
+        // Spirc and the player are rebuilt with the new settings unless they are busy
+        let reannounce = self.connect_config.name != setup.connect_config.name
+            || self.connect_config.device_type != setup.connect_config.device_type;
+        let player_changed = reannounce
+            || self.player_settings != setup.player_settings
+            || self.volume_config != setup.volume_config;
+        // Only players started with an idle timeout have a sink that can be released
+        let idle_changed = setup.meta_config.idle.timeout.is_some()
+            && !self.idle_sink
+            && setup.meta_config.idle != self.meta_config.idle;
+        let running = self.spirc.is_some();
+        let rebuild = running && !self.playing && (player_changed || idle_changed);
+        let player_pending = running && self.playing && player_changed;
+        let idle_pending = running && self.playing && idle_changed;
+
+        let mut normalisation_config = setup.normalisation_config.clone();
+        if player_pending {
+            // The running player keeps applying its own track gain, which this corrects
+            let dsp = self.dsp.lock().unwrap();
+            let current = dsp.normaliser.config();
+            normalisation_config.enabled = current.enabled;
+            normalisation_config.pregain = current.pregain;
+            normalisation_config.target = current.target;
+        }
+        if self.dsp.lock().unwrap().reconfigure(
+            normalisation_config,
+            setup.equalizer_config,
+            setup.channel_config,
+            setup.limiter_config,
+        ) {
+            applied.push("dsp");
+        }
+
+        if *self.login_policy.config() != setup.login_policy_config {
+            self.login_policy.set_config(setup.login_policy_config);
+            applied.push("login-policy");
+        }
+
+        if *self.backoff.config() != setup.reconnect_config {
+            self.backoff = Backoff::new(setup.reconnect_config);
+            applied.push("reconnect");
+        }
+
+        // The device id is derived from the initial device name and stays as announced
+        if self.session_config.proxy != setup.session_config.proxy
+            || self.session_config.ap_port != setup.session_config.ap_port
+        {
+            restart_required.push("session");
+        }
+        if setup.enable_discovery != self.discovery.is_some()
+            || setup.zeroconf_port != self.zeroconf_port
+        {
+            restart_required.push("discovery");
+        }
+        if setup.metrics_address != self.metrics_address {
+            restart_required.push("metrics");
+        }
+        match (&setup.credentials, &self.last_credentials) {
+            (Some(new), Some(current)) if new.username != current.username => {
+                restart_required.push("credentials")
+            }
+            _ => (),
+        }
+
+        // Kept for the next player either way
+        if player_changed {
+            self.player_config = setup.player_config;
+            self.player_settings = setup.player_settings;
+            self.connect_config = setup.connect_config;
+            self.backend = setup.backend;
+            self.device = setup.device;
+            self.mixer = setup.mixer;
+            self.mixer_config = setup.mixer_config;
+            self.volume_config = setup.volume_config;
+            self.normalisation_config = setup.normalisation_config;
+            if player_pending {
+                warn!(
+                    "Player settings only take effect once the config is reloaded while nothing plays"
+                );
+                restart_required.push("player");
+            } else {
+                applied.push("player");
+            }
+        }
+
+        if idle_pending {
+            warn!("The idle timeout only takes effect once the player is restarted");
+            restart_required.push("idle-timeout");
+        }
+
+        if self.meta_config != setup.meta_config {
+            let mut meta_config = setup.meta_config;
+            if let Some(ref mut meta_pipe) = self.meta_pipe {
+                if let Err(e) = meta_pipe.set_config(meta_config.clone()) {
+                    warn!(
+                        "Unable to move metadata pipe to port {}, keeping port {}: {}",
+                        meta_config.port, self.meta_config.port, e
+                    );
+                    meta_config.port = self.meta_config.port;
+                    restart_required.push("metadata-port");
+                }
+            }
+            if self.meta_config != meta_config {
+                let idle_only = MetaPipeConfig {
+                    idle: meta_config.idle.clone(),
+                    ..self.meta_config.clone()
+                } == meta_config;
+                if !(idle_pending && idle_only) {
+                    applied.push("metadata");
+                }
+                self.meta_config = meta_config;
+            }
+        }
+
+        if rebuild {
+            self.restart_player();
+        }
+
+        // Spirc announces the name too, so a busy one keeps the current name
+        if reannounce {
+            let announced = !player_pending
+                && match self.discovery {
+                    None => true,
+                    // A fixed port is still bound by the running discovery server
+                    Some(_) if self.zeroconf_port != 0 => false,
+                    Some(_) => match discovery(
+                        &self.handle,
+                        self.connect_config.clone(),
+                        self.session_config.device_id.clone(),
+                        self.zeroconf_port,
+                    ) {
+                        Ok(discovery) => {
+                            self.discovery = Some(discovery);
+                            true
+                        }
+                        Err(e) => {
+                            warn!("Unable to re-announce device: {}", e);
+                            false
+                        }
+                    },
+                };
+            if announced {
+                applied.push("device-name");
+            } else {
+                restart_required.push("device-name");
+            }
+        }
+
+        if applied.is_empty() {
+            info!("Config reloaded, nothing changed");
+        } else {
+            info!("Config reloaded, applied: {}", applied.join(", "));
+        }
+        if !restart_required.is_empty() {
+            warn!("Changes to {} require a restart", restart_required.join(", "));
+        }
+        let msg = MetaMsgs::config_reloaded {
+            applied: &applied,
+            restart_required: &restart_required,
+        };
+        self.send_meta(serde_json::to_string(&msg).unwrap());
+    }
+
+    // Replaces Spirc and the player on the running session, at the current volume
+    fn restart_player(&mut self) {
+        let session = match self.session.clone() {
+            Some(session) => session,
+            None => return,
+        };
+        info!("Restarting player");
+        if let Some(spirc) = self.spirc.take() {
+            spirc.shutdown();
+        }
+        // Left to finish shutting down, as when reconnecting
+        if let Some(task) = self.spirc_task.take() {
+            current_thread::spawn(Box::new(task));
+        }
+        let volume = self
+            .mixer_volume
+            .as_ref()
+            .map(|volume| volume.load(Ordering::Relaxed));
+        self.start_player(session, volume);
+    }
+
+    fn start_player(&mut self, session: Session, volume: Option<u16>) {
+        let device = self.device.clone();
+        let mixer_config = self.mixer_config.clone();
+        let volume_mixer =
+            VolumeMixer::new((self.mixer)(Some(mixer_config)), self.volume_config.clone());
+        self.mixer_volume = Some(volume_mixer.shared_volume());
+        let mixer: Box<dyn Mixer> = Box::new(volume_mixer);
+        let player_config = self.player_config.clone();
+        let mut connect_config = self.connect_config.clone();
+        if let Some(volume) = volume {
+            connect_config.volume = volume;
+        }
+        // The player's track gain and the normaliser go by the same settings
+        {
+            let mut dsp = self.dsp.lock().unwrap();
+            if *dsp.normaliser.config() != self.normalisation_config {
+                dsp.normaliser.set_config(self.normalisation_config.clone());
+            }
+        }
+
+        // For event hooks
+        let (event_sender, event_receiver) = channel();
+
+        let audio_filter: Option<Box<dyn AudioFilter + Send>> = Some(Box::new(DspFilter::new(
+            mixer.get_audio_filter(),
+            self.dsp.clone(),
+        )));
+        let backend = self.backend;
+        let (idle_sink, sink_control) = if self.meta_config.idle.timeout.is_some() {
+            let (sink, sink_control) = IdleSink::new(backend, device.clone());
+            (Some(sink), Some(sink_control))
+        } else {
+            (None, None)
+        };
+        self.idle_sink = sink_control.is_some();
+        let (player, event_channel) = Player::new(
+            player_config,
+            session.clone(),
+            event_sender.clone(),
+            audio_filter,
+            move || match idle_sink {
+                Some(sink) => Box::new(sink) as Box<dyn Sink>,
+                None => (backend)(device),
+            },
+        );
+
+        let (spirc, spirc_task) =
+            Spirc::new(connect_config, session.clone(), player, mixer, event_sender);
+
+        let spirc_ = Arc::new(spirc);
+
+        // Keep the pipe (and its socket) alive across sessions
+        if let Some(ref mut meta_pipe) = self.meta_pipe {
+            meta_pipe.reconnect(session.clone(), event_receiver, spirc_.clone(), sink_control);
+        } else {
+            self.meta_pipe = Some(MetaPipe::new(
+                self.meta_config.clone(),
+                session.clone(),
+                event_receiver,
+                spirc_.clone(),
+                self.dsp.clone(),
+                self.reload_tx.clone(),
+                sink_control,
+            ));
+        }
+
+        self.spirc = Some(spirc_);
+        self.spirc_task = Some(spirc_task);
+        self.player_event_channel = Some(event_channel);
+        self.playing = false;
+        self.replay_gain = Some(ReplayGain::new(
+            session.clone(),
+            self.player_config.bitrate,
+            self.dsp.clone(),
+        ));
+        self.session = Some(session);
+    }
+
+    fn dump_state(&self) {
+        info!("State dump:");
+        match self.session {
+            Some(ref session) => info!(
+                "  Session[{}]: user <{}>, valid: {}",
+                session.session_id(),
+                session.username(),
+                !session.is_invalid()
+            ),
+            None => info!("  Session: none"),
+        }
+        info!(
+            "  Spirc running: {}, shutting down: {}, reconnect attempt: {}",
+            self.spirc_task.is_some(),
+            self.shutdown,
+            self.backoff.attempt()
+        );
+        info!(
+            "  Discovery: {}, MetaPipe: {}",
+            self.discovery.is_some(),
+            self.meta_pipe.is_some()
+        );
+        info!("  {:?}", self.connect_config);
+        info!("  {:?}", self.player_config);
+        info!("  {:?}", self.mixer_config);
+        info!("  {:?}", self.volume_config);
+        let dsp = self.dsp.lock().unwrap();
+        info!(
+            "  Normalisation: {}, Limiter: {}",
+            dsp.normaliser.is_enabled(),
+            dsp.limiter.is_enabled()
+        );
+        info!("  {:?}", dsp.equalizer.config());
+        info!("  {:?}", dsp.channels.config());
+    }
+
+    fn connect(&mut self, credentials: Credentials, delay: Option<Duration>) {
         let config = self.session_config.clone();
         let handle = self.handle.clone();
+        let cache = self.cache.clone();
+        self.last_credentials = Some(credentials.clone());
 
-        let connection = Session::connect(config, credentials, self.cache.clone(), handle);
+        let connection = match delay {
+            Some(delay) => Box::new(
+                Delay::new(Instant::now() + delay)
+                    .map_err(io::Error::other)
+                    .and_then(move |_| Session::connect(config, credentials, cache, handle)),
+            ),
+            None => Session::connect(config, credentials, cache, handle),
+        };
 
         self.connect = connection;
         self.spirc = None;
@@ -245,6 +831,108 @@ impl Main {
             // tokio::spawn(Box::new(task));
         }
     }
+
+    // The previous user is kept until the new session is up, even while it reconnects
+    fn report_user_change(&self, session: &Session) {
+        let username = session.username();
+        let old = self.login_policy.user();
+        if matches!(old, Some(old) if old.eq_ignore_ascii_case(&username)) {
+            return;
+        }
+        info!("{} takes over from {}", username, old.unwrap_or("nobody"));
+        let msg = MetaMsgs::user_changed {
+            old_username: old.map(|old| self.meta_config.username(old)),
+            new_username: self.meta_config.username(&username),
+            reason: if old.is_some() { "takeover" } else { "login" },
+        };
+        self.send_meta(serde_json::to_string(&msg).unwrap());
+    }
+
+    // Only needed when librespot didn't cache reusable credentials of its own
+    fn save_discovery_login(&self, session: &Session, credentials: &Credentials) {
+        let username = session.username();
+        match self.credential_store.credentials() {
+            Some(ref cached) if cached.username == username => (),
+            _ => {
+                self.credential_store.save(credentials);
+            }
+        }
+    }
+
+    // Unless they are encrypted, librespot caches reusable credentials once
+    // authenticated, prefer those for reconnects over the ones we initially logged in with
+    fn remember_credentials(&mut self, session: &Session) {
+        let username = session.username();
+        if let Some(credentials) = self
+            .credential_store
+            .credentials()
+            .filter(|credentials| credentials.username == username)
+        {
+            debug!("Using cached credentials for {} to reconnect", username);
+            self.last_credentials = Some(credentials);
+        }
+    }
+
+    fn schedule_reconnect(&mut self) {
+        let credentials = match self.last_credentials.clone() {
+            Some(credentials) => credentials,
+            None => {
+                warn!("No credentials to reconnect with");
+                return;
+            }
+        };
+        match self.backoff.next_delay() {
+            Some(delay) => {
+                info!("Reconnecting in {:?} (attempt {})", delay, self.backoff.attempt());
+                METRICS.inc_reconnects();
+                self.report_connection("waiting", Some(delay));
+                self.connect(credentials, Some(delay));
+            }
+            None => {
+                warn!("Reconnect attempts exhausted. Not reconnecting automatically.");
+                self.report_connection("gave_up", None);
+            }
+        }
+    }
+
+    // Ready as soon as we can be discovered, or otherwise once the first session is up
+    fn notify_ready(&mut self) {
+        if !self.ready {
+            self.ready = true;
+            sd_notify::ready();
+        }
+    }
+
+    // Through the pipe thread once there is one, so that messages keep their order
+    fn send_meta(&self, msg: String) {
+        match self.meta_pipe {
+            Some(ref meta_pipe) => meta_pipe.send(msg),
+            None => meta_pipe::send_msg(&self.meta_socket, self.meta_config.port, &msg),
+        }
+    }
+
+    fn report_connection(&self, state: &str, retry_in: Option<Duration>) {
+        METRICS.set_session_state(match state {
+            "connected" => SessionState::Connected,
+            "gave_up" => SessionState::Failed,
+            _ => SessionState::Reconnecting,
+        });
+        match retry_in {
+            Some(delay) => sd_notify::status(&format!(
+                "Connection {} (attempt {}, retrying in {}s)",
+                state,
+                self.backoff.attempt(),
+                delay.as_secs()
+            )),
+            None => sd_notify::status(&format!("Connection {}", state)),
+        }
+        let msg = MetaMsgs::connection {
+            state,
+            attempt: self.backoff.attempt(),
+            retry_in_ms: retry_in.map(|delay| delay.as_millis() as u64),
+        };
+        self.send_meta(serde_json::to_string(&msg).unwrap());
+    }
 }
 
 impl Future for Main {
@@ -256,11 +944,20 @@ impl Future for Main {
             let mut progress = false;
 
             if let Some(Async::Ready(Some(creds))) = self.discovery.as_mut().map(|d| d.poll().unwrap()) {
+                if let Err(reason) = self.login_policy.check(&creds.username) {
+                    warn!("Rejected login by {} ({})", creds.username, reason);
+                    let msg = MetaMsgs::takeover_rejected {
+                        username: self.meta_config.username(&creds.username),
+                        reason,
+                    };
+                    self.send_meta(serde_json::to_string(&msg).unwrap());
+                    continue;
+                }
                 if let Some(ref spirc) = self.spirc {
                     spirc.shutdown();
-                    self.reconnecting = true;
                 }
-                self.auto_connect_times.clear();
+                self.backoff.reset();
+                self.discovery_login = Some(creds.clone());
                 self.credentials(creds);
 
                 progress = true;
@@ -269,48 +966,16 @@ impl Future for Main {
             match self.connect.poll() {
                 Ok(Async::Ready(session)) => {
                     self.connect = Box::new(futures::future::empty());
-                    let device = self.device.clone();
-                    let mixer_config = self.mixer_config.clone();
-                    let mixer = (self.mixer)(Some(mixer_config));
-                    let player_config = self.player_config.clone();
-                    let connect_config = self.connect_config.clone();
-
-                    // For event hooks
-                    let (event_sender, event_receiver) = channel();
-
-                    let audio_filter = mixer.get_audio_filter();
-                    let backend = self.backend;
-                    let (player, event_channel) = Player::new(
-                        player_config,
-                        session.clone(),
-                        event_sender.clone(),
-                        audio_filter,
-                        move || (backend)(device),
-                    );
-
-                    let (spirc, spirc_task) =
-                        Spirc::new(connect_config, session.clone(), player, mixer, event_sender);
-
-                    let spirc_ = Arc::new(spirc);
-
-                    // Todo: improve this
-                    // if !self.reconnecting {
-                    let meta_pipe = MetaPipe::new(
-                        self.meta_config.clone(),
-                        session.clone(),
-                        event_receiver,
-                        spirc_.clone(),
-                    );
-                    self.meta_pipe = Some(meta_pipe);
-                    // } else {
-                    //     self.meta_pipe
-                    //         .reconnect(session.clone(), event_receiver, spirc_.clone());
-                    // }
-
-                    self.spirc = Some(spirc_);
-                    self.spirc_task = Some(spirc_task);
-                    self.player_event_channel = Some(event_channel);
-                    self.session = Some(session);
+                    self.report_connection("connected", None);
+                    self.backoff.connected();
+                    if let Some(credentials) = self.discovery_login.take() {
+                        self.report_user_change(&session);
+                        self.save_discovery_login(&session, &credentials);
+                    }
+                    self.remember_credentials(&session);
+                    self.login_policy.session_started(&session.username());
+                    self.start_player(session, None);
+                    self.notify_ready();
 
                     progress = true;
                 }
@@ -318,23 +983,65 @@ impl Future for Main {
                 Err(error) => {
                     error!("Could not connect to server: {}", error);
                     self.connect = Box::new(futures::future::empty());
+                    self.report_connection("failed", None);
+                    if !self.shutdown {
+                        self.schedule_reconnect();
+                    }
                 }
             }
 
-            if let Async::Ready(Some(())) = self.signal.poll().unwrap() {
-                info!("Ctrl-C received");
-                if !self.shutdown {
-                    if let Some(ref spirc) = self.spirc {
-                        info!("Shutting down spric");
-                        spirc.shutdown();
+            if let Async::Ready(Some(signal)) = self.signal.poll().unwrap() {
+                info!("{:?} received", signal);
+                match signal {
+                    SignalKind::Interrupt | SignalKind::Terminate => {
+                        if !self.shutdown {
+                            sd_notify::stopping();
+                            self.send_meta(MetaMsgs::shutdown.to_string());
+                            if let Some(ref spirc) = self.spirc {
+                                info!("Shutting down spric");
+                                spirc.shutdown();
+                            }
+
+                            self.shutdown = true;
+                            if self.spirc_task.is_none() {
+                                info!("Exiting..");
+                                return Ok(Async::Ready(()));
+                            }
+                        } else {
+                            info!("Exiting..");
+                            return Ok(Async::Ready(()));
+                        }
                     }
+                    SignalKind::Reload => self.reload(),
+                    SignalKind::DumpState => self.dump_state(),
+                }
+
+                progress = true;
+            }
 
-                    self.shutdown = true;
-                } else {
-                    info!("Exiting..");
-                    return Ok(Async::Ready(()));
+            // Only pinged while the event loop keeps getting polled and the
+            // metadata pipe thread keeps going round
+            if let Some(ref mut watchdog) = self.watchdog {
+                match watchdog.poll() {
+                    Ok(Async::Ready(Some(_))) => {
+                        let alive = match self.meta_pipe {
+                            Some(ref meta_pipe) => meta_pipe.is_alive(),
+                            None => true,
+                        };
+                        if alive {
+                            sd_notify::watchdog();
+                        } else {
+                            warn!("Metadata pipe is unresponsive, not pinging the watchdog");
+                        }
+                        progress = true;
+                    }
+                    Ok(_) => (),
+                    Err(e) => warn!("Watchdog timer error: {}", e),
                 }
+            }
 
+            if let Async::Ready(Some(())) = self.reload_rx.poll().unwrap() {
+                self.reload();
                 progress = true;
             }
 
@@ -345,8 +1052,8 @@ impl Future for Main {
                         return Ok(Async::Ready(()));
                     } else {
                         warn!("Spirc shut down unexpectedly");
+                        METRICS.inc_spirc_crashes();
                         drop_spirc_and_try_to_reconnect = true;
-                        self.reconnecting = true;
                     }
                     progress = true;
                 }
@@ -354,26 +1061,36 @@ impl Future for Main {
 
             if drop_spirc_and_try_to_reconnect {
                 self.spirc_task = None;
-                while (!self.auto_connect_times.is_empty())
-                    && ((Instant::now() - self.auto_connect_times[0]).as_secs() > 600)
-                {
-                    let _ = self.auto_connect_times.remove(0);
-                }
-
-                if let Some(credentials) = self.last_credentials.clone() {
-                    if self.auto_connect_times.len() >= 5 {
-                        warn!("Spirc shut down too often. Not reconnecting automatically.");
-                    } else {
-                        self.auto_connect_times.push(Instant::now());
-                        self.credentials(credentials);
-                    }
-                }
+                self.report_connection("disconnected", None);
+                self.schedule_reconnect();
             }
 
-            if let Some(ref mut player_event_channel) = self.player_event_channel {
-                if let Async::Ready(Some(event)) = player_event_channel.poll().unwrap() {
+            let player_event = self
+                .player_event_channel
+                .as_mut()
+                .map(|player_event_channel| player_event_channel.poll().unwrap());
+            match player_event {
+                Some(Async::Ready(Some(event))) => {
                     debug!("PlayerEvent:: {:?}", event);
+                    self.login_policy.handle_event(&event);
+                    match event {
+                        PlayerEvent::Loading { .. }
+                        | PlayerEvent::Started { .. }
+                        | PlayerEvent::Playing { .. } => self.playing = true,
+                        PlayerEvent::Paused { .. } | PlayerEvent::Stopped { .. } => self.playing = false,
+                        _ => (),
+                    }
+                    if let Some(ref replay_gain) = self.replay_gain {
+                        replay_gain.handle_event(&event);
+                    }
+                    if let Some(msg) = meta_pipe::player_event_msg(&event) {
+                        self.send_meta(msg);
+                    }
+                    progress = true;
                 }
+                // The player was dropped
+                Some(Async::Ready(None)) => self.player_event_channel = None,
+                _ => (),
             }
 
             if !progress {
@@ -395,6 +1112,7 @@ fn main() {
     //  Multithread
     // let handle = Handle::default();
 
-    runtime.block_on(Main::new(handle, setup(&args))).unwrap();
+    let (config_source, setup) = setup(&args);
+    runtime.block_on(Main::new(handle, config_source, setup)).unwrap();
     runtime.run().unwrap();
 }
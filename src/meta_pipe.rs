@@ -1,4 +1,15 @@
-use futures::Future;
+use crate::{
+    audio_filter::SharedDsp,
+    channel_mixer::ChannelConfig,
+    equalizer::EqualizerConfig,
+    event_hook::{EventHook, EventHookConfig},
+    idle_sink::{IdleConfig, SinkControl},
+    metrics::METRICS,
+    sd_notify,
+    volume::VolumeConfig,
+    webhook::{WebhookConfig, Webhooks, DEFAULT_EVENTS},
+};
+use futures::{sync::mpsc::UnboundedSender, Future};
 use librespot::{
     connect::spirc::Spirc,
     core::{
@@ -8,18 +19,21 @@ use librespot::{
         spotify_id::{SpotifyAudioType, SpotifyId},
     },
     metadata::{Album, Artist, Episode, Metadata, Show, Track},
+    playback::player::PlayerEvent,
 };
 use serde::Serialize;
 use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
 use std::{
-    io::ErrorKind,
+    io::{self, ErrorKind},
     net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
     sync::{
+        atomic::{AtomicBool, Ordering},
         mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError},
         Arc,
     },
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 // This is not really required at this stage
@@ -43,6 +57,11 @@ pub enum PipeMsgs {
     Next = 0x7,
     Prev = 0x8,
     Volume = 0x9,
+    EqEnable = 0xA,
+    EqGain = 0xB,
+    EqPreamp = 0xC,
+    Balance = 0xD,
+    Reload = 0xE,
 }
 
 #[derive(Debug, Serialize)]
@@ -57,8 +76,48 @@ pub enum MetaMsgs<'a> {
     kSpSinkInactive,
     token(keymaster::Token),
     position_ms(u32),
-    volume(f64),
-    state { status: &'a str },
+    volume {
+        percent: f64,
+        // None when muted
+        db: Option<f64>,
+    },
+    state {
+        status: &'a str,
+    },
+    connection {
+        state: &'a str,
+        attempt: u32,
+        retry_in_ms: Option<u64>,
+    },
+    equalizer(&'a EqualizerConfig),
+    channels(&'a ChannelConfig),
+    session_reconnecting,
+    session_reconnected,
+    shutdown,
+    config_reloaded {
+        applied: &'a [&'a str],
+        restart_required: &'a [&'a str],
+    },
+    player {
+        event: &'a str,
+        track_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        old_track_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        position_ms: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        duration_ms: Option<u32>,
+        timestamp_ms: u64,
+    },
+    takeover_rejected {
+        username: String,
+        reason: &'a str,
+    },
+    user_changed {
+        old_username: Option<String>,
+        new_username: String,
+        reason: &'a str,
+    },
     pong(PipeMsgs), // metadata(String),
 }
 
@@ -68,19 +127,45 @@ impl<'a> std::fmt::Display for MetaMsgs<'a> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct MetaPipeConfig {
     pub port: u16,
     pub version: String,
+    pub volume: VolumeConfig,
+    pub idle: IdleConfig,
+    pub event_hook: EventHookConfig,
+    pub webhooks: Vec<WebhookConfig>,
+    // Report SHA-1 hashes instead of Spotify usernames
+    pub hash_usernames: bool,
+}
+
+impl MetaPipeConfig {
+    pub fn username(&self, username: &str) -> String {
+        if self.hash_usernames {
+            hex::encode(Sha1::digest(username.as_bytes()))
+        } else {
+            username.to_string()
+        }
+    }
 }
 
 pub struct MetaPipe {
     pub thread_handle: Option<thread::JoinHandle<()>>,
     task_tx: Option<Sender<MetaThreadTask>>,
+    heartbeat: Arc<AtomicBool>,
+    port: u16,
 }
 
 enum MetaThreadTask {
-    Reconnect,
+    Reconnect {
+        session: Session,
+        event_rx: Receiver<Event>,
+        spirc: Arc<Spirc>,
+        sink_control: Option<SinkControl>,
+    },
+    // With a socket bound to the new port, if it changed
+    Config(MetaPipeConfig, Option<UdpSocket>),
+    Send(String),
 }
 
 struct MetaPipeThread {
@@ -89,9 +174,26 @@ struct MetaPipeThread {
     task_rx: Receiver<MetaThreadTask>,
     event_rx: Receiver<Event>,
     udp_socket: Option<UdpSocket>,
+    // Set on every round of the loop, cleared by `MetaPipe::is_alive`
+    heartbeat: Arc<AtomicBool>,
     token_info: Option<(Instant, Duration)>,
-    buf: [u8; 2],
+    buf: [u8; 64],
+    buf_len: usize,
     spirc: Arc<Spirc>,
+    dsp: SharedDsp,
+    reload_tx: UnboundedSender<()>,
+    sink_control: Option<SinkControl>,
+    idle_since: Option<Instant>,
+    sink_released: bool,
+    // Between `session_reconnecting` and the next session
+    reconnecting: bool,
+    event_hook: EventHook,
+    // Standby and wake commands, never held up by `onevent` hooks
+    idle_hook: EventHook,
+    webhooks: Option<Webhooks>,
+    // Percent
+    volume: Option<f64>,
+    last_metadata: Option<Value>,
 }
 
 const SCOPES: &str = "streaming,user-read-playback-state,user-modify-playback-state,user-read-currently-playing,user-read-private,user-library-modify,user-top-read,user-read-recently-played,user-library-read,playlist-read-private,playlist-read-collaborative";
@@ -106,8 +208,14 @@ impl MetaPipe {
         session: Session,
         event_rx: Receiver<Event>,
         spirc: Arc<Spirc>,
+        dsp: SharedDsp,
+        reload_tx: UnboundedSender<()>,
+        sink_control: Option<SinkControl>,
     ) -> MetaPipe {
         let (task_tx, task_rx) = channel::<MetaThreadTask>();
+        let heartbeat = Arc::new(AtomicBool::new(true));
+        let thread_heartbeat = heartbeat.clone();
+        let port = config.port;
         let handle = thread::spawn(move || {
             debug!("Starting new MetaPipe[{}]", session.session_id());
 
@@ -117,9 +225,22 @@ impl MetaPipe {
                 task_rx,
                 event_rx,
                 udp_socket: None,
+                heartbeat: thread_heartbeat,
                 token_info: None,
-                buf: [0u8; 2],
+                buf: [0u8; 64],
+                buf_len: 0,
                 spirc,
+                dsp,
+                reload_tx,
+                sink_control,
+                idle_since: None,
+                sink_released: false,
+                reconnecting: false,
+                event_hook: EventHook::default(),
+                idle_hook: EventHook::default(),
+                webhooks: None,
+                volume: None,
+                last_metadata: None,
             };
 
             meta_thread.run();
@@ -128,16 +249,70 @@ impl MetaPipe {
         MetaPipe {
             thread_handle: Some(handle),
             task_tx: Some(task_tx),
+            heartbeat,
+            port,
         }
     }
 
-    #[allow(dead_code)]
-    #[allow(unused_variables)]
-    pub fn reconnect(&mut self, session: Session, event_rx: Receiver<Event>, spirc: Arc<Spirc>) {
-        warn!("Reconnecting with SessionID: {}", session.session_id());
+    // Whether the pipe thread went round its loop since the last call, it
+    // never takes longer than a metadata lookup
+    pub fn is_alive(&self) -> bool {
+        self.heartbeat.swap(false, Ordering::Relaxed)
+    }
+
+    // Hand a new session over to the running pipe thread
+    pub fn reconnect(
+        &mut self,
+        session: Session,
+        event_rx: Receiver<Event>,
+        spirc: Arc<Spirc>,
+        sink_control: Option<SinkControl>,
+    ) {
+        info!("Reconnecting with SessionID: {}", session.session_id());
         if let Some(tx) = &self.task_tx {
-            tx.send(MetaThreadTask::Reconnect)
-                .expect("Failed reconnecting MetaPipe")
+            tx.send(MetaThreadTask::Reconnect {
+                session,
+                event_rx,
+                spirc,
+                sink_control,
+            })
+            .expect("Failed reconnecting MetaPipe")
+        }
+    }
+
+    // Keeps the current port when the new one can't be bound, the rest of
+    // the config is applied either way
+    pub fn set_config(&mut self, mut config: MetaPipeConfig) -> io::Result<()> {
+        let socket = if config.port != self.port {
+            match bind(config.port) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    config.port = self.port;
+                    self.send_config(config, None);
+                    return Err(e);
+                }
+            }
+        } else {
+            None
+        };
+        self.port = config.port;
+        self.send_config(config, socket);
+        Ok(())
+    }
+
+    // From the pipe thread, in order with its own messages
+    pub fn send(&self, msg: String) {
+        if let Some(tx) = &self.task_tx {
+            if tx.send(MetaThreadTask::Send(msg)).is_err() {
+                warn!("MetaPipe thread exited, dropping message");
+            }
+        }
+    }
+
+    fn send_config(&self, config: MetaPipeConfig, socket: Option<UdpSocket>) {
+        if let Some(tx) = &self.task_tx {
+            tx.send(MetaThreadTask::Config(config, socket))
+                .expect("Failed reconfiguring MetaPipe")
         }
     }
 }
@@ -147,17 +322,19 @@ impl MetaPipeThread {
         self.init_socket();
 
         loop {
+            self.heartbeat.store(true, Ordering::Relaxed);
             let mut got_volumio_msg = false;
 
             if self.session.is_invalid() {
                 error!("Session no longer valid");
-                break;
+                if !self.wait_for_session() {
+                    break;
+                }
+                continue;
             }
 
-            match self.task_rx.try_recv() {
-                Ok(_) => (),
-                Err(TryRecvError::Empty) => (),
-                Err(TryRecvError::Disconnected) => break,
+            if !self.handle_tasks() {
+                break;
             }
 
             match self.event_rx.recv_timeout(Duration::from_millis(500)) {
@@ -166,9 +343,14 @@ impl MetaPipeThread {
                 Err(RecvTimeoutError::Disconnected) => {
                     warn!("EventSender disconnected");
                     self.send_meta(&MetaMsgs::kSpPlaybackInactive.to_string());
-                    break;
+                    if !self.wait_for_session() {
+                        break;
+                    }
+                    continue;
                 }
             }
+            self.check_idle();
+
             if let Some(token_info) = self.token_info {
                 if token_info.0.elapsed() > token_info.1 {
                     info!("API Token expired, refreshing...");
@@ -178,7 +360,8 @@ impl MetaPipeThread {
 
             if let Some(ref udp_socket) = self.udp_socket {
                 match udp_socket.recv(&mut self.buf) {
-                    Ok(_nbytes) => {
+                    Ok(nbytes) => {
+                        self.buf_len = nbytes;
                         got_volumio_msg = true;
                     }
                     Err(ref err) if err.kind() != ErrorKind::WouldBlock => warn!("WouldBlock"),
@@ -193,11 +376,87 @@ impl MetaPipeThread {
         self.send_meta(&MetaMsgs::kSpSinkInactive.to_string());
     }
 
+    // Everything that is queued, false once the MetaPipe is dropped
+    fn handle_tasks(&mut self) -> bool {
+        loop {
+            match self.task_rx.try_recv() {
+                Ok(task) => self.handle_task(task),
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    // Blocks until Main hands over a new session, false once the MetaPipe is dropped
+    fn wait_for_session(&mut self) -> bool {
+        self.send_meta(&MetaMsgs::session_reconnecting.to_string());
+        self.reconnecting = true;
+        loop {
+            self.heartbeat.store(true, Ordering::Relaxed);
+            self.drop_volumio_msgs();
+            match self.task_rx.recv_timeout(Duration::from_millis(500)) {
+                Ok(task) => {
+                    let reconnected = matches!(task, MetaThreadTask::Reconnect { .. });
+                    self.handle_task(task);
+                    if reconnected {
+                        return true;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return false,
+            }
+        }
+    }
+
+    // Commands meant for the lost session are dropped rather than replayed on the next one
+    fn drop_volumio_msgs(&mut self) {
+        if let Some(ref udp_socket) = self.udp_socket {
+            while let Ok(nbytes) = udp_socket.recv(&mut self.buf) {
+                info!("Not connected, dropping pipe command {:?}", &self.buf[..nbytes]);
+            }
+        }
+    }
+
+    fn handle_task(&mut self, task: MetaThreadTask) {
+        match task {
+            MetaThreadTask::Reconnect {
+                session,
+                event_rx,
+                spirc,
+                sink_control,
+            } => {
+                debug!(
+                    "MetaPipe[{}] => MetaPipe[{}]",
+                    self.session.session_id(),
+                    session.session_id()
+                );
+                self.session = session;
+                self.event_rx = event_rx;
+                self.spirc = spirc;
+                self.sink_control = sink_control;
+                self.token_info = None;
+                self.idle_since = None;
+                self.sink_released = false;
+                if self.reconnecting {
+                    self.reconnecting = false;
+                    self.send_meta(&MetaMsgs::session_reconnected.to_string());
+                }
+            }
+            MetaThreadTask::Send(msg) => self.send_meta(&msg),
+            MetaThreadTask::Config(config, socket) => {
+                self.config = config;
+                if let Some(socket) = socket {
+                    info!("Moved metadata pipe to port {}", self.config.port);
+                    self.udp_socket = Some(socket);
+                    let ver = self.config.version.clone();
+                    self.send_meta(&ver);
+                }
+            }
+        }
+    }
+
     fn init_socket(&mut self) {
-        // Todo switch to multicast
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), self.config.port + 1);
-        let soc = UdpSocket::bind(addr).expect("Error starting Metadata pipe: ");
-        soc.set_nonblocking(true).expect("Error starting Metadata pipe: ");
+        let soc = bind(self.config.port).expect("Error starting Metadata pipe: ");
         self.udp_socket = Some(soc);
         info!("Metadata pipe established");
         let ver = self.config.version.clone();
@@ -206,6 +465,25 @@ impl MetaPipeThread {
 
     fn handle_event(&mut self, event: Event) {
         info!("Event: {:?}", event);
+        let event_name = format!("{:?}", event);
+        let event_name = event_name
+            .split(|c: char| !c.is_alphanumeric())
+            .next()
+            .unwrap_or_default();
+        METRICS.inc_event(event_name);
+        let (track_id, position_ms) = match event {
+            Event::Load { track_id } | Event::TrackChanged { track_id, .. } => (Some(track_id), None),
+            Event::Play {
+                track_id,
+                position_ms,
+            }
+            | Event::Pause {
+                track_id,
+                position_ms,
+            } => (Some(track_id), Some(position_ms)),
+            Event::Seek { position_ms } => (None, Some(position_ms)),
+            _ => (None, None),
+        };
         match event {
             Event::Load { track_id } => {
                 self.handle_track_id(track_id, None);
@@ -214,6 +492,7 @@ impl MetaPipeThread {
                 track_id,
                 position_ms,
             } => {
+                self.handle_playing();
                 self.send_meta(&serde_json::to_string(&MetaMsgs::state { status: "play" }).unwrap());
                 self.handle_track_id(track_id, Some(position_ms));
             }
@@ -221,6 +500,7 @@ impl MetaPipeThread {
                 track_id,
                 position_ms,
             } => {
+                self.handle_idle();
                 self.send_meta(&serde_json::to_string(&MetaMsgs::state { status: "pause" }).unwrap());
                 self.handle_track_id(track_id, Some(position_ms));
             }
@@ -232,7 +512,10 @@ impl MetaPipeThread {
                 // self.handle_track_id(track_id, None);
                 self.send_meta(&MetaMsgs::kSpPlaybackLoading.to_string())
             }
-            Event::PlaybackStarted { .. } => self.send_meta(&MetaMsgs::kSpPlaybackActive.to_string()),
+            Event::PlaybackStarted { .. } => {
+                self.handle_playing();
+                self.send_meta(&MetaMsgs::kSpPlaybackActive.to_string())
+            }
             Event::SessionActive { .. } => {
                 self.handle_session_active();
                 self.send_meta(&MetaMsgs::kSpDeviceActive.to_string())
@@ -240,7 +523,10 @@ impl MetaPipeThread {
             Event::SessionInactive { .. } => self.send_meta(&MetaMsgs::kSpDeviceInactive.to_string()),
             Event::SinkActive { .. } => self.send_meta(&MetaMsgs::kSpSinkActive.to_string()),
             Event::SinkInactive { .. } => self.send_meta(&MetaMsgs::kSpSinkInactive.to_string()),
-            Event::PlaybackStopped { .. } => self.send_meta(&MetaMsgs::kSpPlaybackInactive.to_string()),
+            Event::PlaybackStopped { .. } => {
+                self.handle_idle();
+                self.send_meta(&MetaMsgs::kSpPlaybackInactive.to_string())
+            }
             Event::Seek { position_ms } => {
                 self.send_meta(&serde_json::to_string(&MetaMsgs::position_ms(position_ms)).unwrap());
             }
@@ -248,10 +534,95 @@ impl MetaPipeThread {
             Event::Volume { volume_to_mixer } => {
                 let pvol = f64::from(volume_to_mixer) / f64::from(u16::max_value()) * 100.0;
                 debug!("Event::Volume({})", pvol);
-                self.send_meta(&serde_json::to_string(&MetaMsgs::volume(pvol)).unwrap());
+                METRICS.set_volume(pvol);
+                self.volume = Some(pvol);
+                let msg = MetaMsgs::volume {
+                    percent: pvol,
+                    db: self.config.volume.to_db(volume_to_mixer),
+                };
+                self.send_meta(&serde_json::to_string(&msg).unwrap());
             }
             _ => debug!("Unhandled Event:: {:?}", event),
         }
+        self.run_event_hook(event_name, track_id, position_ms);
+        self.send_webhooks(event_name, track_id, position_ms);
+    }
+
+    fn send_webhooks(
+        &mut self,
+        event_name: &str,
+        track_id: Option<SpotifyId>,
+        position_ms: Option<u32>,
+    ) {
+        let event = match DEFAULT_EVENTS
+            .iter()
+            .find(|event| **event == snake_case(event_name))
+        {
+            Some(event) => *event,
+            None => return,
+        };
+        if !self.config.webhooks.iter().any(|target| target.wants(event)) {
+            return;
+        }
+
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or_default();
+        let metadata = self.last_metadata.as_ref().filter(|metadata| {
+            track_id.map(|id| id.to_base62()).as_deref() == metadata["track_id"].as_str()
+        });
+        let body = json!({
+            "event": event,
+            "timestamp_ms": timestamp_ms,
+            "track_id": track_id.map(|id| id.to_base62()),
+            "position_ms": position_ms,
+            "volume": self.volume,
+            "metadata": metadata,
+        })
+        .to_string();
+
+        let webhooks = self.webhooks.get_or_insert_with(Webhooks::new);
+        for target in self.config.webhooks.iter().filter(|target| target.wants(event)) {
+            webhooks.send(target, event, body.clone());
+        }
+    }
+
+    fn run_event_hook(&self, event_name: &str, track_id: Option<SpotifyId>, position_ms: Option<u32>) {
+        if self.config.event_hook.command.is_none() {
+            return;
+        }
+        let mut env = vec![("PLAYER_EVENT", snake_case(event_name))];
+        if let Some(track_id) = track_id {
+            env.push(("TRACK_ID", track_id.to_base62()));
+        }
+        if let Some(position_ms) = position_ms {
+            env.push(("POSITION_MS", position_ms.to_string()));
+        }
+        if let Some(volume) = self.volume {
+            env.push(("VOLUME", format!("{:.0}", volume)));
+        }
+        // Metadata is only known once the track has been handled
+        if let Some(metadata) = self.last_metadata.as_ref().filter(|metadata| {
+            track_id.map(|id| id.to_base62()).as_deref() == metadata["track_id"].as_str()
+        }) {
+            let text = |key: &str| metadata[key].as_str().unwrap_or_default().to_string();
+            env.push(("TRACK_NAME", text("track_name")));
+            env.push(("ALBUM_NAME", text("album_name")));
+            env.push(("ARTIST_NAME", artist_names(metadata)));
+            env.push(("DURATION_MS", metadata["duration_ms"].to_string()));
+        }
+        self.event_hook.run(&self.config.event_hook, env);
+    }
+
+    // With the `onevent` timeout
+    fn run_idle_hook(&self, command: Option<String>, event: &'static str) {
+        let config = EventHookConfig {
+            command,
+            ..self.config.event_hook.clone()
+        };
+        self.idle_hook
+            .run(&config, vec![("PLAYER_EVENT", event.to_string())]);
     }
 
     fn handle_volumio_msg(&mut self) {
@@ -294,7 +665,86 @@ impl MetaPipeThread {
                 debug!("{:?}: {:?}[u8] => {:?}[u16]", Volume, volume, vol);
                 self.spirc.volume(vol);
             }
-            _ => debug!("PipeMsg:: {:?}", self.buf),
+            0xA if self.buf_len >= 2 => {
+                let enabled = self.buf[1] != 0;
+                info!("{:?}: {}", EqEnable, enabled);
+                self.dsp.lock().unwrap().equalizer.set_enabled(enabled);
+                self.send_equalizer();
+            }
+            0xB if self.buf_len >= 4 => {
+                // Band index followed by the gain in tenths of a dB (i16 LE)
+                let band = usize::from(self.buf[1]);
+                let gain = f32::from(i16::from_le_bytes([self.buf[2], self.buf[3]])) / 10.0;
+                info!("{:?}: band {} => {}dB", EqGain, band, gain);
+                if !self.dsp.lock().unwrap().equalizer.set_gain(band, gain) {
+                    warn!("No equalizer band {} configured", band);
+                }
+                self.send_equalizer();
+            }
+            0xC if self.buf_len >= 3 => {
+                let preamp = f32::from(i16::from_le_bytes([self.buf[1], self.buf[2]])) / 10.0;
+                info!("{:?}: {}dB", EqPreamp, preamp);
+                self.dsp.lock().unwrap().equalizer.set_preamp(preamp);
+                self.send_equalizer();
+            }
+            0xD if self.buf_len >= 2 => {
+                let balance = self.buf[1] as i8;
+                info!("{:?}: {}", Balance, balance);
+                self.dsp.lock().unwrap().channels.set_balance(balance);
+                let msg = {
+                    let dsp = self.dsp.lock().unwrap();
+                    serde_json::to_string(&MetaMsgs::channels(dsp.channels.config())).unwrap()
+                };
+                self.send_meta(&msg);
+            }
+            0xE => {
+                info!("{:?}", Reload);
+                if self.reload_tx.unbounded_send(()).is_err() {
+                    warn!("Unable to request a config reload");
+                }
+            }
+            _ => debug!("PipeMsg:: {:?}", &self.buf[..self.buf_len]),
+        }
+    }
+
+    fn send_equalizer(&mut self) {
+        let msg = {
+            let dsp = self.dsp.lock().unwrap();
+            serde_json::to_string(&MetaMsgs::equalizer(dsp.equalizer.config())).unwrap()
+        };
+        self.send_meta(&msg);
+    }
+
+    fn handle_playing(&mut self) {
+        self.idle_since = None;
+        if self.sink_released {
+            self.sink_released = false;
+            self.run_idle_hook(self.config.idle.wake_command.clone(), "sink_active");
+            self.send_meta(&MetaMsgs::kSpSinkActive.to_string());
+        }
+    }
+
+    fn handle_idle(&mut self) {
+        if self.idle_since.is_none() {
+            self.idle_since = Some(Instant::now());
+        }
+    }
+
+    // Close the sink once playback has been idle for longer than the configured timeout.
+    // Players started without an idle timeout have no sink that can be released.
+    fn check_idle(&mut self) {
+        if let (Some(timeout), Some(idle_since), Some(sink_control)) = (
+            self.config.idle.timeout,
+            self.idle_since,
+            self.sink_control.as_ref(),
+        ) {
+            if !self.sink_released && idle_since.elapsed() > timeout {
+                info!("Idle for {:?}, releasing sink", timeout);
+                sink_control.release();
+                self.sink_released = true;
+                self.send_meta(&MetaMsgs::kSpSinkInactive.to_string());
+                self.run_idle_hook(self.config.idle.standby_command.clone(), "sink_inactive");
+            }
         }
     }
 
@@ -313,6 +763,7 @@ impl MetaPipeThread {
 
     fn request_access_token(&mut self) {
         debug!("Requesting API access token");
+        METRICS.inc_token_refreshes();
         match CLIENT_ID {
             Some(client_id) => {
                 let token = keymaster::get_token(&self.session, client_id, SCOPES)
@@ -325,7 +776,23 @@ impl MetaPipeThread {
     }
 
     fn handle_track_id(&mut self, track_id: SpotifyId, position_ms: Option<u32>) {
-        if let Some(track_metadata) = self.get_metadata(track_id, position_ms) {
+        let start = Instant::now();
+        let track_metadata = self.get_metadata(track_id, position_ms);
+        match track_metadata {
+            Some(_) => METRICS.observe_metadata(start.elapsed()),
+            None => {
+                warn!("Unable to fetch metadata for {}", track_id.to_base62());
+                METRICS.inc_metadata_failures();
+            }
+        }
+        if let Some(track_metadata) = track_metadata {
+            let metadata = &track_metadata.json["metadata"];
+            sd_notify::status(&format!(
+                "Track: {} - {}",
+                artist_names(metadata),
+                metadata["track_name"].as_str().unwrap_or_default()
+            ));
+            self.last_metadata = Some(metadata.clone());
             self.send_meta(&track_metadata.json.to_string());
             self.send_meta(&"\r\n".to_string());
         }
@@ -333,13 +800,13 @@ impl MetaPipeThread {
 
     fn get_metadata(&mut self, spotify_id: SpotifyId, position_ms: Option<u32>) -> Option<TrackMeta> {
         if spotify_id.audio_type == SpotifyAudioType::Track {
-            let track = Track::get(&self.session, spotify_id).wait().unwrap();
-            let album = Album::get(&self.session, track.album).wait().unwrap();
+            let track = Track::get(&self.session, spotify_id).wait().ok()?;
+            let album = Album::get(&self.session, track.album).wait().ok()?;
             let artists = track
                 .artists
                 .iter()
-                .map(|artist| Artist::get(&self.session, *artist).wait().unwrap())
-                .collect::<Vec<Artist>>();
+                .map(|artist| Artist::get(&self.session, *artist).wait().ok())
+                .collect::<Option<Vec<Artist>>>()?;
             let covers = album
                 .covers
                 .iter()
@@ -353,6 +820,7 @@ impl MetaPipeThread {
                 .iter()
                 .map(|artist| artist.name.clone())
                 .collect::<Vec<String>>();
+            let normalisation_gain = self.normalisation_gain(spotify_id);
             let json = json!(
             { "metadata" : {
                 "track_id": spotify_id.to_base62(),
@@ -364,6 +832,7 @@ impl MetaPipeThread {
                 "duration_ms": track.duration,
                 "albumartId": covers,
                 "position_ms": position_ms.unwrap_or(0),
+                "normalisation_gain_db": normalisation_gain,
             }});
 
             Some(TrackMeta {
@@ -373,14 +842,15 @@ impl MetaPipeThread {
                 json,
             })
         } else {
-            let episode = Episode::get(&self.session, spotify_id).wait().unwrap();
-            let show = Show::get(&self.session, episode.show).wait().unwrap();
+            let episode = Episode::get(&self.session, spotify_id).wait().ok()?;
+            let show = Show::get(&self.session, episode.show).wait().ok()?;
 
             let covers = episode
                 .covers
                 .iter()
                 .map(|cover| cover.to_base16())
                 .collect::<Vec<_>>();
+            let normalisation_gain = self.normalisation_gain(spotify_id);
             let json = json!(
             { "metadata" : {
                 "track_id": spotify_id.to_base62(),
@@ -392,6 +862,7 @@ impl MetaPipeThread {
                 "duration_ms": episode.duration,
                 "albumartId": covers,
                 "position_ms": position_ms.unwrap_or(0),
+                "normalisation_gain_db": normalisation_gain,
             }});
             info!("Json:: {:?}", json);
             Some(TrackMeta {
@@ -403,6 +874,14 @@ impl MetaPipeThread {
         }
     }
 
+    // Known once the player started the item, as long as normalisation is enabled
+    fn normalisation_gain(&self, spotify_id: SpotifyId) -> Option<f32> {
+        match self.dsp.lock().unwrap().normalised {
+            Some((id, gain)) if id == spotify_id => Some(gain),
+            _ => None,
+        }
+    }
+
     fn send_meta(&mut self, msg: &str) {
         let remote_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), self.config.port);
         self.udp_socket
@@ -413,6 +892,96 @@ impl MetaPipeThread {
     }
 }
 
+fn artist_names(metadata: &Value) -> String {
+    metadata["artist_name"]
+        .as_array()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default()
+}
+
+// TrackChanged => track_changed
+fn snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (idx, c) in name.chars().enumerate() {
+        if c.is_uppercase() && idx > 0 {
+            snake.push('_');
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+// Player level lifecycle events, as opposed to the Spirc events handled by the pipe thread
+pub fn player_event_msg(event: &PlayerEvent) -> Option<String> {
+    let (event, track_id, old_track_id, position_ms, duration_ms) = match *event {
+        PlayerEvent::Loading {
+            track_id,
+            position_ms,
+            ..
+        } => ("loading", track_id, None, Some(position_ms), None),
+        PlayerEvent::Started {
+            track_id,
+            position_ms,
+            ..
+        } => ("started", track_id, None, Some(position_ms), None),
+        PlayerEvent::Playing {
+            track_id,
+            position_ms,
+            duration_ms,
+            ..
+        } => ("playing", track_id, None, Some(position_ms), Some(duration_ms)),
+        PlayerEvent::Paused {
+            track_id,
+            position_ms,
+            duration_ms,
+            ..
+        } => ("paused", track_id, None, Some(position_ms), Some(duration_ms)),
+        PlayerEvent::Changed {
+            old_track_id,
+            new_track_id,
+        } => ("changed", new_track_id, Some(old_track_id), None, None),
+        PlayerEvent::EndOfTrack { track_id, .. } => ("end_of_track", track_id, None, None, None),
+        PlayerEvent::Stopped { track_id, .. } => ("stopped", track_id, None, None, None),
+        _ => return None,
+    };
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default();
+    let msg = MetaMsgs::player {
+        event,
+        track_id: track_id.to_base62(),
+        old_track_id: old_track_id.map(|id| id.to_base62()),
+        position_ms,
+        duration_ms,
+        timestamp_ms,
+    };
+    Some(serde_json::to_string(&msg).unwrap())
+}
+
+// Commands arrive one port above the one metadata is sent to
+fn bind(port: u16) -> io::Result<UdpSocket> {
+    // Todo switch to multicast
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port + 1);
+    let soc = UdpSocket::bind(addr)?;
+    soc.set_nonblocking(true)?;
+    Ok(soc)
+}
+
+// For messages sent before there is a MetaPipe
+pub fn send_msg(socket: &UdpSocket, port: u16, msg: &str) {
+    let remote_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+    if let Err(e) = socket.send_to(msg.as_bytes(), remote_addr) {
+        warn!("Unable to send metadata: {}", e);
+    }
+}
+
 impl Drop for MetaPipe {
     fn drop(&mut self) {
         debug!("drop MetaPipe");
@@ -431,8 +1000,8 @@ impl Drop for MetaPipe {
 impl Drop for MetaPipeThread {
     fn drop(&mut self) {
         debug!("drop MetaPipeThread[{}]", self.session.session_id());
-        // Brute force Exit the process so that systemd can restart
-        // warn!("Exiting Vollibrespot");
-        // std::process::exit(1);
+        // Without a heartbeat Main stops pinging the systemd watchdog, which
+        // restarts the daemon
+        sd_notify::status("Metadata pipe closed");
     }
 }
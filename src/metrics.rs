@@ -0,0 +1,225 @@
+use futures::Future;
+use hyper::{service::service_fn_ok, Body, Method, Request, Response, Server, StatusCode};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::runtime::current_thread::Handle;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionState {
+    Idle = 0,
+    Connected = 1,
+    Reconnecting = 2,
+    Failed = 3,
+}
+
+impl SessionState {
+    fn from_u8(state: u8) -> SessionState {
+        match state {
+            1 => SessionState::Connected,
+            2 => SessionState::Reconnecting,
+            3 => SessionState::Failed,
+            _ => SessionState::Idle,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            SessionState::Idle => "idle",
+            SessionState::Connected => "connected",
+            SessionState::Reconnecting => "reconnecting",
+            SessionState::Failed => "failed",
+        }
+    }
+}
+
+// Updated from both the main loop and the metadata pipe thread
+pub struct Metrics {
+    session_state: AtomicU8,
+    reconnects: AtomicU64,
+    spirc_crashes: AtomicU64,
+    token_refreshes: AtomicU64,
+    metadata_fetches: AtomicU64,
+    metadata_failures: AtomicU64,
+    metadata_latency_us: AtomicU64,
+    // f64 bits
+    volume: AtomicU64,
+    events: Mutex<BTreeMap<String, u64>>,
+}
+
+pub static METRICS: Metrics = Metrics {
+    session_state: AtomicU8::new(SessionState::Idle as u8),
+    reconnects: AtomicU64::new(0),
+    spirc_crashes: AtomicU64::new(0),
+    token_refreshes: AtomicU64::new(0),
+    metadata_fetches: AtomicU64::new(0),
+    metadata_failures: AtomicU64::new(0),
+    metadata_latency_us: AtomicU64::new(0),
+    volume: AtomicU64::new(0),
+    events: Mutex::new(BTreeMap::new()),
+};
+
+impl Metrics {
+    pub fn set_session_state(&self, state: SessionState) {
+        self.session_state.store(state as u8, Ordering::Relaxed);
+    }
+
+    pub fn session_state(&self) -> SessionState {
+        SessionState::from_u8(self.session_state.load(Ordering::Relaxed))
+    }
+
+    pub fn inc_reconnects(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_spirc_crashes(&self) {
+        self.spirc_crashes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_token_refreshes(&self) {
+        self.token_refreshes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_metadata(&self, latency: Duration) {
+        self.metadata_fetches.fetch_add(1, Ordering::Relaxed);
+        self.metadata_latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn inc_metadata_failures(&self) {
+        self.metadata_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Percent
+    pub fn set_volume(&self, volume: f64) {
+        self.volume.store(volume.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn inc_event(&self, event: &str) {
+        *self.events.lock().unwrap().entry(event.to_string()).or_insert(0) += 1;
+    }
+
+    // Prometheus text exposition format
+    fn render(&self) -> String {
+        let mut out = String::new();
+        // Samples are (name suffix and labels, value)
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            let _ = writeln!(out, "# HELP vollibrespot_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE vollibrespot_{} {}", name, kind);
+            for (suffix, value) in samples {
+                let _ = writeln!(out, "vollibrespot_{}{} {}", name, suffix, value);
+            }
+        };
+        let load =
+            |counter: &AtomicU64| vec![(String::new(), counter.load(Ordering::Relaxed).to_string())];
+
+        let state = self.session_state();
+        metric(
+            "session_connected",
+            "gauge",
+            "Whether a Spotify session is connected",
+            &[(
+                String::new(),
+                ((state == SessionState::Connected) as u8).to_string(),
+            )],
+        );
+        metric(
+            "session_state",
+            "gauge",
+            "Current session state",
+            &[(format!("{{state=\"{}\"}}", state.as_str()), String::from("1"))],
+        );
+        metric(
+            "reconnects_total",
+            "counter",
+            "Reconnection attempts",
+            &load(&self.reconnects),
+        );
+        metric(
+            "spirc_crashes_total",
+            "counter",
+            "Unexpected Spirc shutdowns",
+            &load(&self.spirc_crashes),
+        );
+        metric(
+            "token_refreshes_total",
+            "counter",
+            "Web API token requests",
+            &load(&self.token_refreshes),
+        );
+        metric(
+            "metadata_fetch_failures_total",
+            "counter",
+            "Failed metadata fetches",
+            &load(&self.metadata_failures),
+        );
+        let latency = self.metadata_latency_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        metric(
+            "metadata_fetch_seconds",
+            "summary",
+            "Latency of successful metadata fetches",
+            &[
+                (String::from("_sum"), latency.to_string()),
+                (
+                    String::from("_count"),
+                    self.metadata_fetches.load(Ordering::Relaxed).to_string(),
+                ),
+            ],
+        );
+        let events = self
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(event, count)| (format!("{{type=\"{}\"}}", event), count.to_string()))
+            .collect::<Vec<_>>();
+        metric("events_total", "counter", "Events handled per type", &events);
+        metric(
+            "volume_percent",
+            "gauge",
+            "Current volume",
+            &[(
+                String::new(),
+                f64::from_bits(self.volume.load(Ordering::Relaxed)).to_string(),
+            )],
+        );
+        out
+    }
+}
+
+fn respond(req: Request<Body>) -> Response<Body> {
+    let (status, body) = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => (StatusCode::OK, METRICS.render()),
+        (&Method::GET, "/healthz") => {
+            let state = METRICS.session_state();
+            let status = match state {
+                SessionState::Idle | SessionState::Connected => StatusCode::OK,
+                SessionState::Reconnecting | SessionState::Failed => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            (status, format!("{}\n", state.as_str()))
+        }
+        _ => (StatusCode::NOT_FOUND, String::from("Not found\n")),
+    };
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+pub fn serve(handle: &Handle, addr: &SocketAddr) -> Result<(), String> {
+    let server = Server::try_bind(addr)
+        .map_err(|e| e.to_string())?
+        .serve(|| service_fn_ok(respond));
+    info!("Serving /metrics and /healthz on http://{}", server.local_addr());
+    handle
+        .spawn(server.map_err(|e| error!("Metrics server error: {}", e)))
+        .map_err(|e| format!("{:?}", e))
+}
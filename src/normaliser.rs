@@ -0,0 +1,247 @@
+use crate::equalizer::db_to_ratio;
+use serde::Serialize;
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    str::FromStr,
+};
+
+// Spotify's ReplayGain values are relative to -14 LUFS
+const REFERENCE_LOUDNESS: f32 = -14.0;
+const NORMALISATION_HEADER_OFFSET: u64 = 144;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalisationType {
+    Track,
+    Album,
+    // Album gain while consecutive items come from the same album
+    Auto,
+}
+
+impl FromStr for NormalisationType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "track" => Ok(NormalisationType::Track),
+            "album" => Ok(NormalisationType::Album),
+            "auto" => Ok(NormalisationType::Auto),
+            _ => Err(format!("Unknown normalisation type: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct NormalisationConfig {
+    pub enabled: bool,
+    pub normalisation_type: NormalisationType,
+    pub pregain: f32,
+    // LUFS
+    pub target: f32,
+    pub limiter: bool,
+}
+
+impl NormalisationConfig {
+    // librespot applies the track gain as each track loads, so that no track
+    // starts at the level of the previous one. It doesn't know about targets.
+    pub fn player_pregain(&self) -> f32 {
+        self.pregain + self.target - REFERENCE_LOUDNESS
+    }
+}
+
+impl Default for NormalisationConfig {
+    fn default() -> NormalisationConfig {
+        NormalisationConfig {
+            enabled: false,
+            normalisation_type: NormalisationType::Track,
+            pregain: 0.0,
+            target: REFERENCE_LOUDNESS,
+            limiter: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NormalisationData {
+    pub track_gain_db: f32,
+    pub track_peak: f32,
+    pub album_gain_db: f32,
+    pub album_peak: f32,
+}
+
+impl NormalisationData {
+    // The gain values live in the header of the (decrypted) Ogg file
+    pub fn parse_from_file<T: Read + Seek>(mut file: T) -> io::Result<NormalisationData> {
+        file.seek(SeekFrom::Start(NORMALISATION_HEADER_OFFSET))?;
+        let mut buf = [0u8; 16];
+        file.read_exact(&mut buf)?;
+        let value = |idx: usize| {
+            f32::from_le_bytes([buf[idx * 4], buf[idx * 4 + 1], buf[idx * 4 + 2], buf[idx * 4 + 3]])
+        };
+        Ok(NormalisationData {
+            track_gain_db: value(0),
+            track_peak: value(1),
+            album_gain_db: value(2),
+            album_peak: value(3),
+        })
+    }
+}
+
+// Corrects the track gain librespot applied towards the album gain, or beyond
+// the peak when the limiter takes care of it
+pub struct Normaliser {
+    config: NormalisationConfig,
+    factor: f32,
+}
+
+impl Normaliser {
+    pub fn new(config: NormalisationConfig) -> Normaliser {
+        Normaliser { config, factor: 1.0 }
+    }
+
+    pub fn config(&self) -> &NormalisationConfig {
+        &self.config
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    // Keeps the current item's correction, the new config applies from the next one
+    pub fn set_config(&mut self, config: NormalisationConfig) {
+        self.config = config;
+    }
+
+    // Back to librespot's track gain until the next item's data is known
+    pub fn reset(&mut self) {
+        self.factor = 1.0;
+    }
+
+    // Returns the applied gain in dB
+    pub fn set_track(&mut self, data: &NormalisationData, album_context: bool) -> f32 {
+        let album = match self.config.normalisation_type {
+            NormalisationType::Track => false,
+            NormalisationType::Album => true,
+            NormalisationType::Auto => album_context,
+        };
+        let (gain_db, peak) = if album {
+            (data.album_gain_db, data.album_peak)
+        } else {
+            (data.track_gain_db, data.track_peak)
+        };
+
+        let gain_db = limit(gain_db + self.config.player_pregain(), peak, self.config.limiter);
+        debug!(
+            "Normalisation ({}): {:?} => {:.2}dB",
+            if album { "album" } else { "track" },
+            data,
+            gain_db
+        );
+        let player_gain_db = limit(
+            data.track_gain_db + self.config.player_pregain(),
+            data.track_peak,
+            false,
+        );
+        self.factor = db_to_ratio(gain_db - player_gain_db);
+        gain_db
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if !self.is_enabled() {
+            return;
+        }
+        for sample in samples.iter_mut() {
+            *sample *= self.factor;
+        }
+    }
+}
+
+// Without the limiter, never amplify beyond the peak, as librespot does
+fn limit(gain_db: f32, peak: f32, limiter: bool) -> f32 {
+    if !limiter && peak > 0.0 && db_to_ratio(gain_db) * peak > 1.0 {
+        -20.0 * peak.log10()
+    } else {
+        gain_db
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: NormalisationData = NormalisationData {
+        track_gain_db: -3.0,
+        track_peak: 0.5,
+        album_gain_db: -6.0,
+        album_peak: 0.9,
+    };
+
+    fn normaliser(normalisation_type: NormalisationType, limiter: bool) -> Normaliser {
+        Normaliser::new(NormalisationConfig {
+            enabled: true,
+            normalisation_type,
+            pregain: 1.0,
+            target: -16.0,
+            limiter,
+        })
+    }
+
+    fn factor(normaliser: &mut Normaliser) -> f32 {
+        let mut samples = [1.0];
+        normaliser.process(&mut samples);
+        samples[0]
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn player_pregain_includes_the_target() {
+        assert_close(
+            normaliser(NormalisationType::Track, true)
+                .config()
+                .player_pregain(),
+            -1.0,
+        );
+    }
+
+    #[test]
+    fn track_gain_is_left_to_librespot() {
+        let mut normaliser = normaliser(NormalisationType::Track, false);
+        assert_close(normaliser.set_track(&DATA, true), -4.0);
+        assert_close(factor(&mut normaliser), 1.0);
+    }
+
+    #[test]
+    fn album_gain_corrects_the_track_gain() {
+        let mut normaliser = normaliser(NormalisationType::Album, true);
+        assert_close(normaliser.set_track(&DATA, false), -7.0);
+        assert_close(factor(&mut normaliser), db_to_ratio(-3.0));
+        normaliser.reset();
+        assert_close(factor(&mut normaliser), 1.0);
+    }
+
+    #[test]
+    fn auto_follows_the_context() {
+        let mut normaliser = normaliser(NormalisationType::Auto, true);
+        assert_close(normaliser.set_track(&DATA, true), -7.0);
+        assert_close(normaliser.set_track(&DATA, false), -4.0);
+    }
+
+    #[test]
+    fn caps_at_the_peak_without_limiter() {
+        let loud = NormalisationData {
+            track_gain_db: 9.0,
+            ..DATA
+        };
+        // +8dB would push the 0.5 peak past full scale, librespot stops at +6dB
+        let mut normaliser = normaliser(NormalisationType::Track, false);
+        assert_close(normaliser.set_track(&loud, false), -20.0 * 0.5f32.log10());
+        assert_close(factor(&mut normaliser), 1.0);
+
+        let mut limited = self::normaliser(NormalisationType::Track, true);
+        assert_close(limited.set_track(&loud, false), 8.0);
+        assert_close(factor(&mut limited), db_to_ratio(8.0 + 20.0 * 0.5f32.log10()));
+    }
+}
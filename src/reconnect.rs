@@ -0,0 +1,155 @@
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+// How long a session has to last before it counts as a recovery
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReconnectConfig {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    // 0 never gives up
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> ReconnectConfig {
+        ReconnectConfig {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(300),
+            max_attempts: 0,
+        }
+    }
+}
+
+// Jittered exponential backoff between consecutive reconnection attempts
+pub struct Backoff {
+    config: ReconnectConfig,
+    attempt: u32,
+    connected_since: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(config: ReconnectConfig) -> Backoff {
+        Backoff {
+            config,
+            attempt: 0,
+            connected_since: None,
+        }
+    }
+
+    pub fn config(&self) -> &ReconnectConfig {
+        &self.config
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+        self.connected_since = None;
+    }
+
+    // Only resets the attempts once the session has lasted, so that a
+    // session that drops right after connecting keeps backing off
+    pub fn connected(&mut self) {
+        self.connected_since = Some(Instant::now());
+    }
+
+    // None once `max_attempts` is exhausted
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if let Some(since) = self.connected_since.take() {
+            if since.elapsed() >= HEALTHY_AFTER {
+                self.attempt = 0;
+            }
+        }
+        if self.config.max_attempts > 0 && self.attempt >= self.config.max_attempts {
+            return None;
+        }
+        let exp = 2u32.saturating_pow(self.attempt.min(31));
+        let delay = self
+            .config
+            .initial_delay
+            .checked_mul(exp)
+            .map_or(self.config.max_delay, |delay| delay.min(self.config.max_delay));
+        self.attempt += 1;
+
+        // Equal jitter: somewhere between half and the full delay
+        let half = delay / 2;
+        let jitter_ms = half.as_millis() as u64;
+        let jitter = if jitter_ms > 0 {
+            Duration::from_millis(rand::thread_rng().gen_range(0, jitter_ms + 1))
+        } else {
+            Duration::from_millis(0)
+        };
+        Some(half + jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(max_attempts: u32) -> Backoff {
+        Backoff::new(ReconnectConfig {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            max_attempts,
+        })
+    }
+
+    #[test]
+    fn jitters_between_half_and_full_delay() {
+        for _ in 0..100 {
+            let mut backoff = backoff(0);
+            for full in &[1, 2, 4, 8] {
+                let delay = backoff.next_delay().unwrap();
+                let full = Duration::from_secs(*full);
+                assert!(delay >= full / 2 && delay <= full, "{:?} for {:?}", delay, full);
+            }
+        }
+    }
+
+    #[test]
+    fn caps_at_max_delay() {
+        let mut backoff = backoff(0);
+        for _ in 0..40 {
+            let delay = backoff.next_delay().unwrap();
+            assert!(delay <= Duration::from_secs(10));
+        }
+        assert!(backoff.next_delay().unwrap() >= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut backoff = backoff(3);
+        for _ in 0..3 {
+            assert!(backoff.next_delay().is_some());
+        }
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.attempt(), 3);
+        backoff.reset();
+        assert!(backoff.next_delay().is_some());
+    }
+
+    #[test]
+    fn short_sessions_keep_backing_off() {
+        let mut backoff = backoff(0);
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.connected();
+        assert!(backoff.next_delay().unwrap() >= Duration::from_secs(2));
+        assert_eq!(backoff.attempt(), 3);
+    }
+
+    #[test]
+    fn healthy_sessions_reset() {
+        let mut backoff = backoff(0);
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.connected_since = Instant::now().checked_sub(HEALTHY_AFTER);
+        assert!(backoff.next_delay().unwrap() <= Duration::from_secs(1));
+        assert_eq!(backoff.attempt(), 1);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{audio_filter::SharedDsp, normaliser::NormalisationData};
+use futures::Future;
+use librespot::{
+    audio::{AudioDecrypt, AudioFile},
+    core::{
+        session::Session,
+        spotify_id::{FileId, SpotifyAudioType, SpotifyId},
+    },
+    metadata::{Episode, FileFormat, Metadata, Track},
+    playback::{config::Bitrate, player::PlayerEvent},
+};
+use std::{
+    collections::VecDeque,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+// The playing item, the one before it and whatever was preloaded
+const CACHED_ITEMS: usize = 4;
+
+enum Request {
+    Prefetch(SpotifyId),
+    Play(SpotifyId),
+}
+
+// Reads the ReplayGain header of the file the player streams on its own
+// thread, as soon as the player loads or preloads an item, so that the
+// correction is usually in place before the item starts
+pub struct ReplayGain {
+    tx: Sender<Request>,
+}
+
+impl ReplayGain {
+    pub fn new(session: Session, bitrate: Bitrate, dsp: SharedDsp) -> ReplayGain {
+        let (tx, rx) = channel();
+        // The same file the player picks for the bitrate
+        let format = match bitrate {
+            Bitrate::Bitrate96 => FileFormat::OGG_VORBIS_96,
+            Bitrate::Bitrate160 => FileFormat::OGG_VORBIS_160,
+            Bitrate::Bitrate320 => FileFormat::OGG_VORBIS_320,
+        };
+        let fetcher = Fetcher {
+            session,
+            format,
+            dsp,
+            items: Items::default(),
+            current: None,
+        };
+        thread::spawn(move || fetcher.run(rx));
+        ReplayGain { tx }
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        let request = match *event {
+            PlayerEvent::Loading { track_id, .. }
+            | PlayerEvent::TimeToPreloadNextTrack { track_id, .. } => Request::Prefetch(track_id),
+            PlayerEvent::Started { track_id, .. } | PlayerEvent::Playing { track_id, .. } => {
+                Request::Play(track_id)
+            }
+            PlayerEvent::Changed { new_track_id, .. } => Request::Play(new_track_id),
+            _ => return,
+        };
+        // The thread only ends once this is dropped
+        let _ = self.tx.send(request);
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Item {
+    id: SpotifyId,
+    // None for episodes
+    album: Option<SpotifyId>,
+    data: NormalisationData,
+}
+
+#[derive(Default)]
+struct Items(VecDeque<Item>);
+
+impl Items {
+    fn get(&self, id: SpotifyId) -> Option<Item> {
+        self.0.iter().find(|item| item.id == id).copied()
+    }
+
+    fn insert(&mut self, item: Item) {
+        self.0.retain(|cached| cached.id != item.id);
+        if self.0.len() == CACHED_ITEMS {
+            self.0.pop_front();
+        }
+        self.0.push_back(item);
+    }
+
+    // There is no context to go by, items that follow one from the same album
+    // are taken to be played from that album
+    fn album_context(&self, previous: Option<SpotifyId>, item: &Item) -> bool {
+        item.album.is_some()
+            && previous
+                .and_then(|previous| self.get(previous))
+                .map(|previous| previous.album)
+                == Some(item.album)
+    }
+}
+
+struct Fetcher {
+    session: Session,
+    format: FileFormat,
+    dsp: SharedDsp,
+    items: Items,
+    current: Option<SpotifyId>,
+}
+
+impl Fetcher {
+    fn run(mut self, rx: Receiver<Request>) {
+        for request in rx {
+            if !self.dsp.lock().unwrap().normaliser.is_enabled() {
+                continue;
+            }
+            match request {
+                Request::Prefetch(id) => {
+                    self.item(id);
+                }
+                Request::Play(id) if self.current != Some(id) => self.play(id),
+                Request::Play(_) => (),
+            }
+        }
+    }
+
+    fn play(&mut self, id: SpotifyId) {
+        let previous = self.current.replace(id);
+        // Never carry the previous item's correction over
+        {
+            let mut dsp = self.dsp.lock().unwrap();
+            dsp.normaliser.reset();
+            dsp.normalised = None;
+        }
+        let item = match self.item(id) {
+            Some(item) => item,
+            None => return,
+        };
+        let album_context = self.items.album_context(previous, &item);
+        let mut dsp = self.dsp.lock().unwrap();
+        let gain = dsp.normaliser.set_track(&item.data, album_context);
+        dsp.normalised = Some((id, gain));
+    }
+
+    fn item(&mut self, id: SpotifyId) -> Option<Item> {
+        if let Some(item) = self.items.get(id) {
+            return Some(item);
+        }
+        match self.fetch(id) {
+            Ok(item) => {
+                self.items.insert(item);
+                Some(item)
+            }
+            Err(e) => {
+                warn!("Unable to read normalisation data for {}: {}", id.to_base62(), e);
+                None
+            }
+        }
+    }
+
+    fn fetch(&self, id: SpotifyId) -> Result<Item, String> {
+        let (album, files) = if id.audio_type == SpotifyAudioType::Track {
+            let track = Track::get(&self.session, id)
+                .wait()
+                .map_err(|e| format!("{:?}", e))?;
+            (Some(track.album), track.files)
+        } else {
+            let episode = Episode::get(&self.session, id)
+                .wait()
+                .map_err(|e| format!("{:?}", e))?;
+            (None, episode.files)
+        };
+        let file_id = files
+            .get(&self.format)
+            .copied()
+            .ok_or_else(|| format!("no {:?} file", self.format))?;
+        Ok(Item {
+            id,
+            album,
+            data: self.read_header(id, file_id)?,
+        })
+    }
+
+    fn read_header(&self, id: SpotifyId, file_id: FileId) -> Result<NormalisationData, String> {
+        let key = self
+            .session
+            .audio_key()
+            .request(id, file_id)
+            .wait()
+            .map_err(|e| format!("{:?}", e))?;
+        let file = AudioFile::open(&self.session, file_id, 40 * 1024, true)
+            .wait()
+            .map_err(|e| format!("{:?}", e))?;
+        NormalisationData::parse_from_file(AudioDecrypt::new(key, file)).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: u128, album: Option<u128>) -> Item {
+        let spotify_id = |id| SpotifyId {
+            id,
+            audio_type: SpotifyAudioType::Track,
+        };
+        Item {
+            id: spotify_id(id),
+            album: album.map(spotify_id),
+            data: NormalisationData::default(),
+        }
+    }
+
+    #[test]
+    fn keeps_the_latest_items() {
+        let mut items = Items::default();
+        for id in 0..6 {
+            items.insert(item(id, None));
+        }
+        items.insert(item(3, Some(1)));
+        assert_eq!(items.0.len(), CACHED_ITEMS);
+        assert!(items.get(item(1, None).id).is_none());
+        assert_eq!(items.get(item(3, None).id).unwrap().album, item(0, Some(1)).album);
+    }
+
+    #[test]
+    fn album_context_follows_the_previous_item() {
+        let mut items = Items::default();
+        items.insert(item(1, Some(10)));
+        items.insert(item(2, None));
+        let previous = Some(item(1, None).id);
+        assert!(items.album_context(previous, &item(3, Some(10))));
+        assert!(!items.album_context(previous, &item(3, Some(11))));
+        assert!(!items.album_context(None, &item(3, Some(10))));
+        // Episodes have no album
+        assert!(!items.album_context(Some(item(2, None).id), &item(4, None)));
+    }
+}
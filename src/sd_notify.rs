@@ -0,0 +1,109 @@
+use std::{
+    env, io,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    process,
+    time::Duration,
+};
+
+// Minimal implementation of systemd's notify protocol, a no-op unless we are
+// started with `$NOTIFY_SOCKET` set (`Type=notify` units).
+fn send(state: &str) -> io::Result<()> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound()?;
+    // Abstract namespace sockets are prefixed with '@'
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+    socket.send_to_addr(state.as_bytes(), &addr).map(|_| ())
+}
+
+fn notify(state: &str) {
+    if let Err(e) = send(state) {
+        warn!("Unable to notify systemd: {}", e);
+    }
+}
+
+pub fn ready() {
+    notify("READY=1");
+}
+
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+pub fn watchdog() {
+    notify("WATCHDOG=1");
+}
+
+pub fn status(status: &str) {
+    // Each line is a separate assignment
+    notify(&format!("STATUS={}", status.replace('\n', " ")));
+}
+
+// Interval at which to ping the watchdog, half of `WatchdogSec` as systemd recommends
+pub fn watchdog_interval() -> Option<Duration> {
+    env::var("NOTIFY_SOCKET").ok()?;
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != process::id() {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec / 2)).filter(|interval| *interval > Duration::from_millis(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buf = [0u8; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    // One test, the environment is shared between threads
+    #[test]
+    fn notifies_the_socket() {
+        env::remove_var("NOTIFY_SOCKET");
+        assert!(send("READY=1").is_ok());
+        assert_eq!(watchdog_interval(), None);
+
+        let path = env::temp_dir().join(format!("vollibrespot-notify-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        env::set_var("NOTIFY_SOCKET", &path);
+        ready();
+        assert_eq!(receive(&socket), "READY=1");
+        status("Playing\nsomething");
+        assert_eq!(receive(&socket), "STATUS=Playing something");
+        watchdog();
+        assert_eq!(receive(&socket), "WATCHDOG=1");
+
+        let name = format!("vollibrespot-notify-{}", process::id());
+        let addr = SocketAddr::from_abstract_name(&name).unwrap();
+        let abstract_socket = UnixDatagram::bind_addr(&addr).unwrap();
+        env::set_var("NOTIFY_SOCKET", format!("@{}", name));
+        stopping();
+        assert_eq!(receive(&abstract_socket), "STOPPING=1");
+
+        env::set_var("WATCHDOG_USEC", "10000000");
+        env::set_var("WATCHDOG_PID", process::id().to_string());
+        assert_eq!(watchdog_interval(), Some(Duration::from_secs(5)));
+        env::set_var("WATCHDOG_PID", "0");
+        assert_eq!(watchdog_interval(), None);
+
+        env::remove_var("NOTIFY_SOCKET");
+        env::remove_var("WATCHDOG_USEC");
+        env::remove_var("WATCHDOG_PID");
+        fs::remove_file(&path).unwrap();
+    }
+}
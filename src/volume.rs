@@ -0,0 +1,166 @@
+use librespot::playback::mixer::{softmixer::SoftMixer, AudioFilter, Mixer, MixerConfig};
+use serde::Serialize;
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeCurve {
+    Linear,
+    Log,
+    Cubic,
+    Fixed,
+}
+
+impl FromStr for VolumeCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "linear" => Ok(VolumeCurve::Linear),
+            "log" | "logarithmic" => Ok(VolumeCurve::Log),
+            "cubic" => Ok(VolumeCurve::Cubic),
+            "fixed" => Ok(VolumeCurve::Fixed),
+            _ => Err(format!("Unknown volume curve: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for VolumeCurve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            VolumeCurve::Linear => "linear",
+            VolumeCurve::Log => "log",
+            VolumeCurve::Cubic => "cubic",
+            VolumeCurve::Fixed => "fixed",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct VolumeConfig {
+    pub curve: VolumeCurve,
+    pub min_db: f64,
+    pub max_db: f64,
+}
+
+// Also the config file's defaults
+impl Default for VolumeConfig {
+    fn default() -> VolumeConfig {
+        VolumeConfig {
+            curve: VolumeCurve::Log,
+            min_db: -60.0,
+            max_db: 0.0,
+        }
+    }
+}
+
+impl VolumeConfig {
+    // Amplitude ratio (0.0..=1.0) for a Spotify volume
+    pub fn to_ratio(&self, volume: u16) -> f64 {
+        let x = f64::from(volume) / f64::from(u16::max_value());
+        let max = 10f64.powf(self.max_db / 20.0);
+        match self.curve {
+            VolumeCurve::Linear => x * max,
+            VolumeCurve::Cubic => x.powi(3) * max,
+            VolumeCurve::Fixed => max,
+            VolumeCurve::Log if volume == 0 => 0.0,
+            VolumeCurve::Log => 10f64.powf((self.min_db + (self.max_db - self.min_db) * x) / 20.0),
+        }
+    }
+
+    pub fn to_mixer(&self, volume: u16) -> u16 {
+        (self.to_ratio(volume) * f64::from(u16::max_value())).round() as u16
+    }
+
+    // None when muted
+    pub fn to_db(&self, volume: u16) -> Option<f64> {
+        let ratio = self.to_ratio(volume);
+        if ratio > 0.0 {
+            Some(20.0 * ratio.log10())
+        } else {
+            None
+        }
+    }
+}
+
+// Applies the configured curve in front of librespot's mixers, which are all
+// driven linearly by Spirc
+pub struct VolumeMixer {
+    mixer: Box<dyn Mixer>,
+    config: VolumeConfig,
+    volume: Arc<AtomicU16>,
+}
+
+impl VolumeMixer {
+    pub fn new(mixer: Box<dyn Mixer>, config: VolumeConfig) -> VolumeMixer {
+        VolumeMixer {
+            mixer,
+            config,
+            volume: Arc::new(AtomicU16::new(0)),
+        }
+    }
+
+    // Spirc's volume, readable once the mixer is handed over
+    pub fn shared_volume(&self) -> Arc<AtomicU16> {
+        self.volume.clone()
+    }
+}
+
+impl Mixer for VolumeMixer {
+    fn open(config: Option<MixerConfig>) -> VolumeMixer {
+        VolumeMixer::new(Box::new(SoftMixer::open(config)), VolumeConfig::default())
+    }
+
+    fn start(&self) {
+        self.mixer.start()
+    }
+
+    fn stop(&self) {
+        self.mixer.stop()
+    }
+
+    fn volume(&self) -> u16 {
+        self.volume.load(Ordering::Relaxed)
+    }
+
+    fn set_volume(&self, volume: u16) {
+        self.volume.store(volume, Ordering::Relaxed);
+        self.mixer.set_volume(self.config.to_mixer(volume))
+    }
+
+    fn get_audio_filter(&self) -> Option<Box<dyn AudioFilter + Send>> {
+        self.mixer.get_audio_filter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curve_names_parse_back() {
+        for &curve in &[
+            VolumeCurve::Linear,
+            VolumeCurve::Log,
+            VolumeCurve::Cubic,
+            VolumeCurve::Fixed,
+        ] {
+            assert_eq!(VolumeCurve::from_str(&curve.to_string()), Ok(curve));
+        }
+    }
+
+    #[test]
+    fn log_spans_the_db_range() {
+        let config = VolumeConfig::default();
+        assert_eq!(config.to_db(0), None);
+        assert!((config.to_db(u16::max_value()).unwrap() - config.max_db).abs() < 1e-9);
+        assert!((config.to_db(u16::max_value() / 2).unwrap() + 30.0).abs() < 0.01);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::{config_parser::Secret, crypto::hmac_sha256, version};
+use futures::{
+    future::{self, Either, Loop},
+    sync::mpsc::{unbounded, UnboundedSender},
+    Future, Stream,
+};
+use hyper::{client::HttpConnector, Body, Client, Method, Request, Uri};
+use hyper_tls::HttpsConnector;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::{
+    runtime::current_thread::{self, Runtime},
+    timer::{Delay, Timeout},
+};
+
+pub const DEFAULT_EVENTS: &[&str] = &[
+    "track_changed",
+    "play",
+    "pause",
+    "volume",
+    "session_active",
+    "session_inactive",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebhookConfig {
+    pub url: Uri,
+    pub events: Vec<String>,
+    // Signs the body with HMAC-SHA256
+    pub secret: Option<Secret>,
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+impl WebhookConfig {
+    pub fn wants(&self, event: &str) -> bool {
+        self.events.iter().any(|wanted| wanted == event)
+    }
+}
+
+type Delivery = (WebhookConfig, &'static str, String);
+
+// Takes both http:// and https:// urls
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+// Deliveries run on their own thread so that slow targets never hold up the pipe
+pub struct Webhooks {
+    tx: UnboundedSender<Delivery>,
+}
+
+impl Webhooks {
+    pub fn new() -> Webhooks {
+        let (tx, rx) = unbounded::<Delivery>();
+        thread::spawn(move || {
+            let mut runtime = Runtime::new().expect("Unable to start webhook runtime");
+            let client = match HttpsConnector::new(1) {
+                Ok(connector) => Client::builder().build(connector),
+                Err(e) => {
+                    error!("Unable to set up TLS for webhooks: {}", e);
+                    return;
+                }
+            };
+            let _ = runtime.block_on(rx.for_each(move |(target, event, body)| {
+                current_thread::spawn(deliver(client.clone(), target, event, body));
+                Ok(())
+            }));
+            debug!("Webhook thread exited");
+        });
+        Webhooks { tx }
+    }
+
+    pub fn send(&self, target: &WebhookConfig, event: &'static str, body: String) {
+        if self.tx.unbounded_send((target.clone(), event, body)).is_err() {
+            warn!("Webhook thread exited, dropping {} for {}", event, target.url);
+        }
+    }
+}
+
+fn deliver(
+    client: HttpsClient,
+    target: WebhookConfig,
+    event: &'static str,
+    body: String,
+) -> impl Future<Item = (), Error = ()> {
+    future::loop_fn(0, move |attempt| {
+        let target = target.clone();
+        post(&client, &target, event, &body).then(move |result| match result {
+            Ok(()) => Either::A(future::ok(Loop::Break(()))),
+            Err(e) if attempt < target.retries => {
+                let delay = Duration::from_secs(1 << attempt.min(6));
+                warn!(
+                    "Webhook {} to {} failed: {}, retrying in {:?}",
+                    event, target.url, e, delay
+                );
+                Either::B(
+                    Delay::new(Instant::now() + delay).then(move |_| Ok(Loop::Continue(attempt + 1))),
+                )
+            }
+            Err(e) => {
+                warn!("Webhook {} to {} failed: {}, giving up", event, target.url, e);
+                Either::A(future::ok(Loop::Break(())))
+            }
+        })
+    })
+}
+
+fn post(
+    client: &HttpsClient,
+    target: &WebhookConfig,
+    event: &str,
+    body: &str,
+) -> impl Future<Item = (), Error = String> {
+    let mut request = Request::builder();
+    request
+        .method(Method::POST)
+        .uri(target.url.clone())
+        .header("Content-Type", "application/json")
+        .header("User-Agent", format!("vollibrespot/{}", version::semver()))
+        .header("X-Vollibrespot-Event", event);
+    if let Some(ref secret) = target.secret {
+        let signature = hmac_sha256(secret.expose().as_bytes(), body.as_bytes());
+        request.header(
+            "X-Vollibrespot-Signature",
+            format!("sha256={}", hex::encode(signature)),
+        );
+    }
+    let request = request.body(Body::from(body.to_string())).unwrap();
+
+    Timeout::new(client.request(request), target.timeout).then(|result| match result {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => Err(format!("HTTP {}", response.status())),
+        Err(ref e) if e.is_elapsed() => Err(String::from("timed out")),
+        Err(e) => Err(e.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_parser::Secret;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc::{channel, Receiver},
+    };
+
+    // Answers one request per connection with the next status, and never
+    // with 0. Hands back each request's head and body.
+    fn serve(statuses: Vec<u16>) -> (Uri, Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut held = Vec::new();
+            for (status, stream) in statuses.into_iter().zip(listener.incoming()) {
+                let mut stream = BufReader::new(stream.unwrap());
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    stream.read_line(&mut head).unwrap();
+                }
+                let length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |length| length.parse().unwrap());
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).unwrap();
+                tx.send((head, String::from_utf8(body).unwrap())).unwrap();
+
+                let mut stream = stream.into_inner();
+                if status == 0 {
+                    held.push(stream);
+                    continue;
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+            thread::sleep(Duration::from_secs(5));
+        });
+        (url.parse().unwrap(), rx)
+    }
+
+    fn target(url: Uri, secret: Option<&str>) -> WebhookConfig {
+        WebhookConfig {
+            url,
+            events: vec![String::from("play")],
+            secret: secret.map(|secret| toml::Value::from(secret).try_into::<Secret>().unwrap()),
+            timeout: Duration::from_millis(500),
+            retries: 1,
+        }
+    }
+
+    fn client() -> HttpsClient {
+        Client::builder().build(HttpsConnector::new(1).unwrap())
+    }
+
+    #[test]
+    fn signs_the_body() {
+        let (url, requests) = serve(vec![200]);
+        let target = target(url, Some("secret"));
+        let body = String::from("{\"event\":\"play\"}");
+        let mut runtime = Runtime::new().unwrap();
+        assert_eq!(runtime.block_on(post(&client(), &target, "play", &body)), Ok(()));
+
+        let (head, received) = requests.recv().unwrap();
+        assert_eq!(received, body);
+        assert!(head.starts_with("POST /hook HTTP/1.1\r\n"), "{}", head);
+        assert!(head.contains("x-vollibrespot-event: play\r\n"), "{}", head);
+        let signature = hex::encode(hmac_sha256(b"secret", body.as_bytes()));
+        assert!(
+            head.contains(&format!("x-vollibrespot-signature: sha256={}\r\n", signature)),
+            "{}",
+            head
+        );
+    }
+
+    #[test]
+    fn retries_failed_deliveries() {
+        let (url, requests) = serve(vec![500, 200]);
+        let mut runtime = Runtime::new().unwrap();
+        let delivery = deliver(client(), target(url, None), "play", String::from("{}"));
+        assert_eq!(runtime.block_on(delivery), Ok(()));
+        assert_eq!(requests.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn gives_up_on_slow_targets() {
+        let (url, requests) = serve(vec![0]);
+        let mut runtime = Runtime::new().unwrap();
+        let result = runtime.block_on(post(&client(), &target(url, None), "play", "{}"));
+        assert_eq!(result, Err(String::from("timed out")));
+        assert_eq!(requests.try_iter().count(), 1);
+    }
+}